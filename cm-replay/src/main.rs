@@ -0,0 +1,127 @@
+use std::{env, path::PathBuf, process::ExitCode};
+
+use anyhow::{anyhow, bail, Result};
+use cm_shared_data::STATE_HASH_INTERVAL;
+use cm_sim::{game::Game, map::Map, replay::Replay, simulation::Simulation};
+
+const USAGE: &str =
+    "Usage: cm-replay <replay file> [--every <ticks>] [--ticks <ticks>] [--expect <hash>]";
+
+struct Args {
+    path: PathBuf,
+    // Print the state hash every this many ticks
+    every: i32,
+    // How many ticks to run, defaults to just past the last input
+    ticks: Option<i32>,
+    // Final hash the replay must reproduce
+    expect: Option<u64>,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut path = None;
+    let mut every = STATE_HASH_INTERVAL;
+    let mut ticks = None;
+    let mut expect = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "--every" => every = value()?.parse()?,
+            "--ticks" => ticks = Some(value()?.parse()?),
+            "--expect" => expect = Some(u64::from_str_radix(&value()?, 16)?),
+            _ if path.is_none() => path = Some(PathBuf::from(&arg)),
+            _ => bail!("Unexpected argument {}", arg),
+        }
+    }
+
+    if every <= 0 {
+        bail!("--every must be positive");
+    }
+    Ok(Args {
+        path: path.ok_or_else(|| anyhow!("Missing replay file"))?,
+        every,
+        ticks,
+        expect,
+    })
+}
+
+/// Runs the replay through the sim as fast as possible and returns the final
+/// state hash.
+fn play(replay: Replay, args: &Args) -> Result<u64> {
+    let last_tick = args.ticks.unwrap_or_else(|| {
+        replay
+            .inputs
+            .iter()
+            .map(|input| input.for_tick + 1)
+            .max()
+            .unwrap_or(0)
+    });
+
+    let map = Map::parse(&replay.header.map)?;
+    // Every player's bundles were confirmed when it was recorded, only the
+    // inputs themselves are needed to play it back
+    let mut sim = Simulation::new(Game::with_map(replay.header.tick_duration, map));
+    for input in replay.inputs {
+        sim.buffer_input(input);
+    }
+
+    while sim.current_tick() < last_tick {
+        let next_report = (sim.current_tick() / args.every + 1) * args.every;
+        if let Err(stalled) = sim.advance_to(next_report.min(last_tick)) {
+            bail!("Replay stalled on tick {}", stalled.tick);
+        }
+        if sim.current_tick() % args.every == 0 {
+            println!(
+                "tick {}: {:016x}",
+                sim.current_tick(),
+                sim.game().checksum()
+            );
+        }
+    }
+
+    let hash = sim.game().checksum();
+    println!("final tick {}: {:016x}", sim.current_tick(), hash);
+    Ok(hash)
+}
+
+fn run(args: Args) -> Result<()> {
+    let replay = Replay::load(&args.path)?;
+    println!(
+        "Replaying {} ({} inputs, {:?} per tick, players {:?})",
+        args.path.display(),
+        replay.inputs.len(),
+        replay.header.tick_duration,
+        replay.header.players
+    );
+
+    let hash = play(replay, &args)?;
+    if let Some(expected) = args.expect {
+        if hash != expected {
+            bail!(
+                "Final hash {:016x} does not match expected {:016x}",
+                hash,
+                expected
+            );
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
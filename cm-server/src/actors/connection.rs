@@ -2,7 +2,8 @@ use std::time::SystemTime;
 
 use anyhow::Result;
 use cm_shared_data::{
-    read_message, ClientLobbyMessage, ClientNetworkMessage, ServerNetworkMessage,
+    read_message, ClientLobbyMessage, ClientNetworkMessage, JoinLobbyError, LobbyPlayer,
+    ServerNetworkMessage, TickBundle,
 };
 use ractor::{async_trait, Actor, ActorProcessingErr, ActorRef};
 use tracing::info;
@@ -14,8 +15,24 @@ pub enum ConnectionMessage {
     JoinedLobby {
         name: String,
         lobby_ref: ActorRef<LobbyMessage>,
+        player_id: i32,
+        other_players: Vec<LobbyPlayer>,
     },
-    SendSynchronizedGameStart(SystemTime),
+    JoinLobbyFailed {
+        name: String,
+        reason: JoinLobbyError,
+    },
+    SendSynchronizedGameStart {
+        start_at: SystemTime,
+        players: Vec<i32>,
+        map: String,
+    },
+    SendLobbyRoster(Vec<LobbyPlayer>),
+    // The lobby we're in is closing
+    LobbyClosed(String),
+    SendTickBundle(TickBundle),
+    SendDesyncDetected(i32),
+    SendInputDelay(i32),
     LostConnection,
 }
 
@@ -23,6 +40,22 @@ pub struct ConnectionState {
     connection: quinn::Connection,
     server_ref: ActorRef<ServerMessage>,
     lobby_ref: Option<ActorRef<LobbyMessage>>,
+    // Assigned by the lobby on joining, the only player we accept input for
+    player_id: Option<i32>,
+}
+
+impl ConnectionState {
+    /// Passes a message on to our lobby if we're in one. A lobby that's
+    /// closed can't be sent to, so we leave it rather than failing ourselves.
+    fn cast_to_lobby(&mut self, message: LobbyMessage) {
+        if let Some(ref lobby) = self.lobby_ref {
+            if lobby.cast(message).is_err() {
+                info!("Lobby has closed, leaving it");
+                self.lobby_ref = None;
+                self.player_id = None;
+            }
+        }
+    }
 }
 
 pub struct ConnectionArguments {
@@ -57,9 +90,8 @@ impl Actor for ConnectionActor {
                     Ok(()) => {}
                     Err(_) => {
                         info!("Received error reading message, losing connection");
-                        myself
-                            .cast(ConnectionMessage::LostConnection)
-                            .expect("Failed to send lost connection");
+                        // Fails if we've already stopped, nothing left to clean up then
+                        let _ = myself.cast(ConnectionMessage::LostConnection);
                         break;
                     }
                 }
@@ -71,6 +103,7 @@ impl Actor for ConnectionActor {
         Ok(ConnectionState {
             connection,
             lobby_ref: None,
+            player_id: None,
             server_ref: arguments.server_ref,
         })
     }
@@ -86,40 +119,129 @@ impl Actor for ConnectionActor {
                 info!("Received message: {:?}", msg);
                 match msg {
                     ClientNetworkMessage::LobbyMessage(ClientLobbyMessage::RequestStartGame) => {
-                        if let Some(ref lobby) = state.lobby_ref {
-                            lobby.cast(LobbyMessage::RequestStartGame)?;
+                        if let Some(player_id) = state.player_id {
+                            state.cast_to_lobby(LobbyMessage::RequestStartGame { player_id });
                         }
                     }
                     ClientNetworkMessage::LobbyMessage(ClientLobbyMessage::CreateLobby {
                         name,
+                        player_name,
+                    }) => {
+                        state.server_ref.cast(ServerMessage::CreateLobby {
+                            name,
+                            host: myself,
+                            host_name: player_name,
+                        })?;
+                    }
+                    ClientNetworkMessage::LobbyMessage(ClientLobbyMessage::JoinLobby {
+                        name,
+                        player_name,
                     }) => {
-                        state
-                            .server_ref
-                            .cast(ServerMessage::CreateLobby { name, host: myself })?;
+                        state.server_ref.cast(ServerMessage::JoinLobby {
+                            name,
+                            conn: myself,
+                            player_name,
+                        })?;
+                    }
+                    ClientNetworkMessage::InputMessage(input) => {
+                        if let Some(player_id) = state.player_id {
+                            state.cast_to_lobby(LobbyMessage::Input { player_id, input });
+                        }
+                    }
+                    ClientNetworkMessage::Ping { sent_at } => {
+                        let mut send = state.connection.open_uni().await?;
+                        let bytes = ServerNetworkMessage::pong(sent_at, SystemTime::now())?;
+                        send.write_all(&bytes).await?;
+                        send.finish().await?;
+
+                        // Pings are regular enough to keep the lobby up to
+                        // date on our latency too
+                        if let Some(player_id) = state.player_id {
+                            let rtt = state.connection.rtt();
+                            state.cast_to_lobby(LobbyMessage::PlayerLatency { player_id, rtt });
+                        }
+                    }
+                    ClientNetworkMessage::StateHash {
+                        start_at,
+                        tick,
+                        hash,
+                    } => {
+                        state.cast_to_lobby(LobbyMessage::PlayerStateHash {
+                            player: myself.get_id(),
+                            start_at,
+                            tick,
+                            hash,
+                        });
                     }
-                    _ => {}
                 };
             }
-            ConnectionMessage::JoinedLobby { name, lobby_ref } => {
-                info!("Joined lobby {}", name);
+            ConnectionMessage::JoinedLobby {
+                name,
+                lobby_ref,
+                player_id,
+                other_players,
+            } => {
+                info!("Joined lobby {} as player {}", name, player_id);
                 state.lobby_ref = Some(lobby_ref);
+                state.player_id = Some(player_id);
+                let mut send = state.connection.open_uni().await?;
+                let bytes = ServerNetworkMessage::lobby_joined(name, player_id, other_players)?;
+                send.write_all(&bytes).await?;
+                send.finish().await?;
+            }
+            ConnectionMessage::JoinLobbyFailed { name, reason } => {
+                info!("Failed to join lobby {}: {}", name, reason);
                 let mut send = state.connection.open_uni().await?;
-                // FIXME: handle other players
-                let bytes = ServerNetworkMessage::lobby_joined(name, vec![])?;
+                let bytes = ServerNetworkMessage::join_lobby_failed(name, reason)?;
                 send.write_all(&bytes).await?;
                 send.finish().await?;
             }
-            ConnectionMessage::SendSynchronizedGameStart(start_at) => {
+            ConnectionMessage::SendSynchronizedGameStart {
+                start_at,
+                players,
+                map,
+            } => {
                 let mut send = state.connection.open_uni().await?;
-                let bytes = ServerNetworkMessage::synchronized_game_start(start_at)?;
+                let bytes = ServerNetworkMessage::synchronized_game_start(start_at, players, map)?;
+                send.write_all(&bytes).await?;
+                send.finish().await?;
+            }
+            ConnectionMessage::SendLobbyRoster(players) => {
+                let mut send = state.connection.open_uni().await?;
+                let bytes = ServerNetworkMessage::lobby_roster_changed(players)?;
+                send.write_all(&bytes).await?;
+                send.finish().await?;
+            }
+            ConnectionMessage::LobbyClosed(name) => {
+                info!("Lobby {} closed", name);
+                state.lobby_ref = None;
+                state.player_id = None;
+                let mut send = state.connection.open_uni().await?;
+                let bytes = ServerNetworkMessage::lobby_closed(name)?;
+                send.write_all(&bytes).await?;
+                send.finish().await?;
+            }
+            ConnectionMessage::SendTickBundle(bundle) => {
+                let mut send = state.connection.open_uni().await?;
+                let bytes = ServerNetworkMessage::tick_bundle(bundle)?;
+                send.write_all(&bytes).await?;
+                send.finish().await?;
+            }
+            ConnectionMessage::SendDesyncDetected(tick) => {
+                let mut send = state.connection.open_uni().await?;
+                let bytes = ServerNetworkMessage::desync_detected(tick)?;
+                send.write_all(&bytes).await?;
+                send.finish().await?;
+            }
+            ConnectionMessage::SendInputDelay(ticks) => {
+                let mut send = state.connection.open_uni().await?;
+                let bytes = ServerNetworkMessage::input_delay_changed(ticks)?;
                 send.write_all(&bytes).await?;
                 send.finish().await?;
             }
             ConnectionMessage::LostConnection => {
                 info!("Connection lost");
-                if let Some(l) = &state.lobby_ref {
-                    l.cast(LobbyMessage::LostConnection(myself.get_id()))?;
-                }
+                state.cast_to_lobby(LobbyMessage::LostConnection(myself.get_id()));
                 state
                     .server_ref
                     .cast(ServerMessage::LostConnection(myself.get_id()))?;
@@ -1,35 +1,270 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Result;
+use cm_shared_data::{
+    bundle_lead_ticks, input_delay_ticks, Input, InputBundle, JoinLobbyError, LobbyPlayer,
+    TickBundle, TICK_DURATION,
+};
 use cm_sim::{
-    actor::{SimActor, SimArguments, SimMessage},
+    actor::{SimActor, SimArguments, SimEvent, SimMessage},
     game::Game,
+    map::Map,
+    replay::new_replay_path,
+    simulation::{LateInputPolicy, SimSnapshot},
 };
 use ractor::{async_trait, Actor, ActorId, ActorProcessingErr, ActorRef};
-use tokio::sync::watch;
-use tracing::info;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
 
 use super::{connection::ConnectionMessage, server::ServerMessage};
+use crate::{
+    desync::{DesyncDetector, HashSource},
+    validation::InputValidator,
+};
+
+// Including the host, player ids are the slots 0..MAX_PLAYERS
+const MAX_PLAYERS: i32 = 8;
+// The host always takes the first slot
+const HOST_PLAYER_ID: i32 = 0;
+
+/// A connection in the lobby and the name its player goes by
+pub struct LobbyMember {
+    pub conn: ActorRef<ConnectionMessage>,
+    pub name: String,
+}
 
 pub enum LobbyMessage {
-    AddPlayer(ActorRef<ConnectionMessage>),
-    RequestStartGame,
+    AddPlayer(LobbyMember),
+    // Only the host can start the game, or restart it
+    RequestStartGame {
+        player_id: i32,
+    },
     LostConnection(ActorId),
+    // An input from the connection that was assigned `player_id`
+    Input {
+        player_id: i32,
+        input: Input,
+    },
+    CloseTicks,
+    // Hashes are for the game that started at `start_at`, reports for any
+    // game but the current one are dropped
+    ServerStateHash {
+        start_at: SystemTime,
+        tick: i32,
+        hash: u64,
+    },
+    PlayerStateHash {
+        player: ActorId,
+        start_at: SystemTime,
+        tick: i32,
+        hash: u64,
+    },
+    PlayerLatency {
+        player_id: i32,
+        rtt: Duration,
+    },
 }
 
 pub struct LobbyState {
     server_ref: ActorRef<ServerMessage>,
     name: String,
-    host_conn: ActorRef<ConnectionMessage>,
-    player_conns: Vec<ActorRef<ConnectionMessage>>,
+    map: Map,
+    // Keyed by player id
+    members: BTreeMap<i32, LobbyMember>,
     sim: Option<ActorRef<SimMessage>>,
-    game_state_receiver: Option<watch::Receiver<(i32, Game)>>,
+    game_state_receiver: Option<watch::Receiver<SimSnapshot>>,
+    // When the current game starts, by our clock
+    started_at: Option<SystemTime>,
+    // Forwarding the current sim's events and snapshots to us
+    sim_tasks: Vec<JoinHandle<()>>,
+    desync_detector: DesyncDetector,
+    input_validator: InputValidator,
+    // Sparse by tick, inputs received for ticks that haven't been closed yet
+    pending_inputs: BTreeMap<i32, Vec<Input>>,
+    // The earliest tick whose bundle hasn't been sent out yet
+    next_open_tick: i32,
+    // How many ticks ahead everyone's told to schedule inputs, only ever
+    // raised so nobody's inputs suddenly start landing out of order
+    input_delay: i32,
+}
+
+impl LobbyState {
+    fn all_conns(&self) -> impl Iterator<Item = &ActorRef<ConnectionMessage>> {
+        self.members.values().map(|m| &m.conn)
+    }
+
+    /// Every player in id order, the host first
+    fn roster(&self) -> Vec<LobbyPlayer> {
+        self.members
+            .iter()
+            .map(|(&player_id, m)| LobbyPlayer {
+                player_id,
+                name: m.name.clone(),
+            })
+            .collect()
+    }
+
+    /// The lowest slot nobody is in
+    fn free_player_id(&self) -> Option<i32> {
+        (0..MAX_PLAYERS).find(|id| !self.members.contains_key(id))
+    }
+
+    fn broadcast_roster(&self) -> Result<()> {
+        let roster = self.roster();
+        for c in self.all_conns() {
+            c.cast(ConnectionMessage::SendLobbyRoster(roster.clone()))?;
+        }
+        Ok(())
+    }
+
+    /// How fast the server sim is running, normal speed before the game's
+    /// started
+    fn speed(&self) -> f32 {
+        self.game_state_receiver
+            .as_ref()
+            .map(|rx| rx.borrow().speed)
+            .unwrap_or(1.0)
+    }
+
+    fn player_ids(&self) -> Vec<i32> {
+        self.members.keys().copied().collect()
+    }
+
+    /// Validates an input against the server sim and queues it for the next
+    /// bundle, dropping it if it's invalid or there's no game running.
+    fn receive_input(&mut self, player_id: i32, input: Input) {
+        if let Some(ref rx) = self.game_state_receiver {
+            let result = self
+                .input_validator
+                .validate(player_id, &input, &rx.borrow());
+            match result {
+                Ok(()) => self.buffer_input(input),
+                Err(reason) => warn!(
+                    "Dropped input from player {} in lobby {}, {} ({} dropped so far)",
+                    player_id,
+                    self.name,
+                    reason,
+                    self.input_validator.rejections(player_id)
+                ),
+            }
+        }
+    }
+
+    fn buffer_input(&mut self, mut input: Input) {
+        // Too late for the tick it asked for, the server decides it happens
+        // on the earliest tick still open instead
+        if input.for_tick < self.next_open_tick {
+            input.for_tick = self.next_open_tick;
+        }
+        self.pending_inputs
+            .entry(input.for_tick)
+            .or_default()
+            .push(input);
+    }
+
+    /// Sends out bundles for every tick up to `BUNDLE_LEAD` past the server
+    /// sim's current tick, at the speed it's running.
+    fn close_ticks(&mut self) -> Result<()> {
+        let (Some(sim), Some(rx)) = (&self.sim, &self.game_state_receiver) else {
+            return Ok(());
+        };
+        let close_until = {
+            let snapshot = rx.borrow();
+            snapshot.tick + bundle_lead_ticks(snapshot.speed)
+        };
+
+        while self.next_open_tick < close_until {
+            let tick = self.next_open_tick;
+            let inputs = self.pending_inputs.remove(&tick).unwrap_or_default();
+            let bundle = TickBundle {
+                tick,
+                bundles: self
+                    .player_ids()
+                    .into_iter()
+                    .map(|player_id| InputBundle {
+                        for_tick: tick,
+                        player_id,
+                        inputs: inputs
+                            .iter()
+                            .filter(|i| i.player_id == player_id)
+                            .copied()
+                            .collect(),
+                    })
+                    .collect(),
+            };
+
+            sim.cast(SimMessage::SendTickBundle(bundle.clone()))?;
+            for c in self.all_conns() {
+                c.cast(ConnectionMessage::SendTickBundle(bundle.clone()))?;
+            }
+            self.next_open_tick += 1;
+        }
+        Ok(())
+    }
+
+    /// Raises the lobby wide input delay if a player's latency needs more
+    fn report_latency(&mut self, player_id: i32, rtt: Duration) -> Result<()> {
+        // quinn's rtt is smoothed so leave some margin for it varying
+        let needed = input_delay_ticks(rtt, rtt / 4, self.speed());
+        if needed > self.input_delay {
+            info!(
+                "Raising input delay in lobby {} to {} ticks for player {} ({:?} rtt)",
+                self.name, needed, player_id, rtt
+            );
+            self.input_delay = needed;
+            for c in self.all_conns() {
+                c.cast(ConnectionMessage::SendInputDelay(needed))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the sim and everything forwarding from it
+    fn stop_sim(&mut self) {
+        for task in self.sim_tasks.drain(..) {
+            task.abort();
+        }
+        if let Some(sim) = self.sim.take() {
+            // Failing means it's already stopped
+            let _ = sim.cast(SimMessage::Stop);
+        }
+    }
+
+    fn report_state_hash(
+        &mut self,
+        source: HashSource,
+        start_at: SystemTime,
+        tick: i32,
+        hash: u64,
+    ) -> Result<()> {
+        // Left over from before a restart
+        if self.started_at != Some(start_at) {
+            return Ok(());
+        }
+        if let Some(desync_tick) = self.desync_detector.report(source, tick, hash) {
+            warn!(
+                "Desync detected in lobby {} at tick {} (reported by {:?})",
+                self.name, desync_tick, source
+            );
+            for c in self.all_conns() {
+                c.cast(ConnectionMessage::SendDesyncDetected(desync_tick))?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct LobbyArguments {
     pub server_ref: ActorRef<ServerMessage>,
     pub name: String,
-    pub host_conn: ActorRef<ConnectionMessage>,
+    pub map: Map,
+    pub host: LobbyMember,
 }
 
 pub struct LobbyActor;
@@ -47,17 +282,26 @@ impl Actor for LobbyActor {
     ) -> Result<Self::State, ActorProcessingErr> {
         info!("Creating lobby");
         let state_name = arguments.name.clone();
-        arguments.host_conn.cast(ConnectionMessage::JoinedLobby {
+        arguments.host.conn.cast(ConnectionMessage::JoinedLobby {
             name: arguments.name,
             lobby_ref: myself,
+            player_id: HOST_PLAYER_ID,
+            other_players: vec![],
         })?;
         Ok(LobbyState {
             server_ref: arguments.server_ref,
             name: state_name,
-            host_conn: arguments.host_conn,
-            player_conns: vec![],
+            map: arguments.map,
+            members: BTreeMap::from([(HOST_PLAYER_ID, arguments.host)]),
             sim: None,
             game_state_receiver: None,
+            started_at: None,
+            sim_tasks: vec![],
+            desync_detector: DesyncDetector::new(),
+            input_validator: InputValidator::new(),
+            pending_inputs: BTreeMap::new(),
+            next_open_tick: 0,
+            input_delay: input_delay_ticks(Duration::ZERO, Duration::ZERO, 1.0),
         })
     }
 
@@ -68,51 +312,202 @@ impl Actor for LobbyActor {
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
-            LobbyMessage::AddPlayer(player_conn) => {
-                if let Ok(()) = player_conn.cast(ConnectionMessage::JoinedLobby {
-                    lobby_ref: myself,
-                    name: state.name.clone(),
-                }) {
-                    state.player_conns.push(player_conn);
+            LobbyMessage::AddPlayer(player) => {
+                let slot = if state.sim.is_some() {
+                    Err(JoinLobbyError::GameStarted)
+                } else {
+                    state.free_player_id().ok_or(JoinLobbyError::Full)
+                };
+                match slot {
+                    Err(reason) => {
+                        player.conn.cast(ConnectionMessage::JoinLobbyFailed {
+                            name: state.name.clone(),
+                            reason,
+                        })?;
+                    }
+                    Ok(player_id) => {
+                        if let Ok(()) = player.conn.cast(ConnectionMessage::JoinedLobby {
+                            lobby_ref: myself,
+                            name: state.name.clone(),
+                            player_id,
+                            other_players: state.roster(),
+                        }) {
+                            info!(
+                                "{} joined lobby {} as player {}",
+                                player.name, state.name, player_id
+                            );
+                            player
+                                .conn
+                                .cast(ConnectionMessage::SendInputDelay(state.input_delay))?;
+                            state.members.insert(player_id, player);
+                            state.broadcast_roster()?;
+                        }
+                    }
                 }
             }
-            LobbyMessage::RequestStartGame => {
-                let (state_tx, state_rx) =
-                    watch::channel((0, Game::new(Duration::from_millis(22))));
+            LobbyMessage::RequestStartGame { player_id } if player_id != HOST_PLAYER_ID => {
+                warn!(
+                    "Player {} asked to start the game in lobby {} but isn't the host",
+                    player_id, state.name
+                );
+            }
+            LobbyMessage::RequestStartGame { .. } => {
+                // Starting over, the last game's sim and anything still
+                // queued up for it go
+                if state.sim.is_some() {
+                    info!("Restarting game in lobby {}", state.name);
+                    state.stop_sim();
+                    state.game_state_receiver = None;
+                    state.pending_inputs.clear();
+                    state.next_open_tick = 0;
+                    state.desync_detector = DesyncDetector::new();
+                }
+
+                let (state_tx, state_rx) = watch::channel(SimSnapshot::new(
+                    0,
+                    Game::with_map(TICK_DURATION, state.map.clone()),
+                ));
+                let (event_tx, mut event_rx) = mpsc::unbounded_channel();
                 let (actor, _) = Actor::spawn(
                     None,
                     SimActor,
                     SimArguments {
-                        minimum_tick_duration: Duration::from_millis(22),
+                        minimum_tick_duration: TICK_DURATION,
                         game_state_sender: state_tx,
+                        players: state.player_ids(),
+                        map: state.map.clone(),
+                        // Named by the lobby actor, not anything a client sent
+                        replay_path: Some(new_replay_path(&format!(
+                            "server-{}",
+                            myself.get_id().pid()
+                        ))),
+                        // Late inputs were already rescheduled before they
+                        // were bundled, the sim has to process exactly what
+                        // the clients do
+                        late_input_policy: LateInputPolicy::Reject,
+                        event_sender: Some(event_tx),
                     },
                 )
                 .await
                 .expect("Failed to start sim");
 
-                // Synchronize start for all clients
                 let start_at = SystemTime::now() + Duration::from_secs(5);
-                state
-                    .host_conn
-                    .cast(ConnectionMessage::SendSynchronizedGameStart(start_at))?;
-                for c in state.player_conns.iter() {
-                    c.cast(ConnectionMessage::SendSynchronizedGameStart(start_at))?;
+                let lobby_name = state.name.clone();
+                let lobby = myself.clone();
+                let events_task = tokio::spawn(async move {
+                    while let Some(event) = event_rx.recv().await {
+                        match event {
+                            SimEvent::LateInput(late) => warn!(
+                                "Server sim for lobby {} dropped input for tick {} on tick {}",
+                                lobby_name, late.input.for_tick, late.current_tick
+                            ),
+                            SimEvent::ReplayFailed(e) => {
+                                warn!("Server sim for lobby {}: {}", lobby_name, e)
+                            }
+                            // Feed the server's own hashes into desync detection
+                            SimEvent::StateHash { tick, hash } => {
+                                if lobby
+                                    .cast(LobbyMessage::ServerStateHash {
+                                        start_at,
+                                        tick,
+                                        hash,
+                                    })
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                            }
+                            // Clients see these in their own sims
+                            SimEvent::Game { .. } => {}
+                        }
+                    }
+                });
+
+                // Synchronize start for all clients
+                for c in state.all_conns() {
+                    c.cast(ConnectionMessage::SendSynchronizedGameStart {
+                        start_at,
+                        players: state.player_ids(),
+                        map: state.map.source().to_string(),
+                    })?;
                 }
+                // Keep closing ticks ahead of the server sim as it advances,
+                // however fast it's going. Closing catches up on every tick
+                // since the last so it's fine to miss snapshots.
+                let mut sim_rx = state_rx.clone();
+                let snapshots_task = tokio::spawn(async move {
+                    while sim_rx.changed().await.is_ok() {
+                        if myself.cast(LobbyMessage::CloseTicks).is_err() {
+                            break;
+                        }
+                    }
+                });
+
                 // Synchronize server sim
                 actor.cast(SimMessage::StartAt(start_at))?;
                 state.sim = Some(actor);
                 state.game_state_receiver = Some(state_rx);
+                state.started_at = Some(start_at);
+                state.sim_tasks = vec![events_task, snapshots_task];
+                state.close_ticks()?;
             }
-            // For now losing any connection kills the whole lobby
+            // Losing the host or anyone mid game kills the whole lobby,
             // we'll deal with disconnected states and handling this later
-            LobbyMessage::LostConnection(_) => {
-                info!("Closing lobby: {}", state.name);
-                state
-                    .server_ref
-                    .cast(ServerMessage::LobbyClosed(state.name.clone()))?;
-                myself.stop(Some("Lost connection".to_string()));
+            LobbyMessage::LostConnection(id) => {
+                let host_id = state.members.get(&HOST_PLAYER_ID).map(|h| h.conn.get_id());
+                if state.sim.is_some() || host_id == Some(id) {
+                    info!("Closing lobby: {}", state.name);
+                    // Everyone still here has to go back to finding a lobby.
+                    // They may be disconnecting too, which is no reason not
+                    // to close.
+                    for c in state.all_conns().filter(|c| c.get_id() != id) {
+                        let _ = c.cast(ConnectionMessage::LobbyClosed(state.name.clone()));
+                    }
+                    state
+                        .server_ref
+                        .cast(ServerMessage::LobbyClosed(state.name.clone()))?;
+                    myself.stop(Some("Lost connection".to_string()));
+                } else {
+                    state.members.retain(|_, m| m.conn.get_id() != id);
+                    state.broadcast_roster()?;
+                }
+            }
+            LobbyMessage::Input { player_id, input } => {
+                state.receive_input(player_id, input);
+            }
+            LobbyMessage::CloseTicks => {
+                state.close_ticks()?;
+            }
+            LobbyMessage::ServerStateHash {
+                start_at,
+                tick,
+                hash,
+            } => {
+                state.report_state_hash(HashSource::Server, start_at, tick, hash)?;
+            }
+            LobbyMessage::PlayerStateHash {
+                player,
+                start_at,
+                tick,
+                hash,
+            } => {
+                state.report_state_hash(HashSource::Player(player), start_at, tick, hash)?;
+            }
+            LobbyMessage::PlayerLatency { player_id, rtt } => {
+                state.report_latency(player_id, rtt)?;
             }
         };
         Ok(())
     }
+
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        // The sim isn't linked to us, it'd keep ticking with nobody to
+        // send it bundles
+        state.stop_sim();
+        Ok(())
+    }
 }
@@ -2,13 +2,15 @@ use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::Result;
 
+use cm_shared_data::JoinLobbyError;
+use cm_sim::map::Map;
 use quinn::{Endpoint, TransportConfig};
 use ractor::{async_trait, Actor, ActorId, ActorProcessingErr, ActorRef};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use super::{
     connection::{ConnectionActor, ConnectionArguments, ConnectionMessage},
-    lobby::{LobbyActor, LobbyArguments, LobbyMessage},
+    lobby::{LobbyActor, LobbyArguments, LobbyMember, LobbyMessage},
 };
 
 static SERVER_NAME: &str = "localhost";
@@ -23,17 +25,35 @@ fn generate_self_signed_cert() -> Result<(rustls::Certificate, rustls::PrivateKe
     Ok((rustls::Certificate(cert.serialize_der()?), key))
 }
 
+// Names end up in logs and on everyone's screen, keep them to something
+// that can be seen
+fn valid_lobby_name(name: &str) -> bool {
+    !name.trim().is_empty() && !name.chars().any(char::is_control)
+}
+
 pub enum ServerMessage {
     NewConnection(quinn::Connecting),
     CreateLobby {
         name: String,
         host: ActorRef<ConnectionMessage>,
+        host_name: String,
+    },
+    JoinLobby {
+        name: String,
+        conn: ActorRef<ConnectionMessage>,
+        player_name: String,
     },
     LostConnection(ActorId),
     LobbyClosed(String),
 }
 
+pub struct ServerArguments {
+    // What every lobby plays on
+    pub map: Map,
+}
+
 pub struct ServerState {
+    map: Map,
     connection_actors: Vec<ActorRef<ConnectionMessage>>,
     lobbies: HashMap<String, ActorRef<LobbyMessage>>,
 }
@@ -44,12 +64,12 @@ pub struct ServerActor;
 impl Actor for ServerActor {
     type State = ServerState;
     type Msg = ServerMessage;
-    type Arguments = ();
+    type Arguments = ServerArguments;
 
     async fn pre_start(
         &self,
         myself: ActorRef<Self::Msg>,
-        _arguments: Self::Arguments,
+        arguments: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
         let (cert, key_der) = generate_self_signed_cert()?;
         let server_crypto = rustls::ServerConfig::builder()
@@ -76,6 +96,7 @@ impl Actor for ServerActor {
         });
 
         Ok(ServerState {
+            map: arguments.map,
             connection_actors: vec![],
             lobbies: HashMap::new(),
         })
@@ -101,22 +122,68 @@ impl Actor for ServerActor {
                 .expect("Connection actor failed to start");
                 state.connection_actors.push(actor);
             }
-            ServerMessage::CreateLobby { name, host } => {
+            ServerMessage::CreateLobby {
+                name,
+                host,
+                host_name,
+            } => {
+                let refusal = if !valid_lobby_name(&name) {
+                    Some(JoinLobbyError::BadName)
+                } else if state.lobbies.contains_key(&name) {
+                    Some(JoinLobbyError::NameTaken)
+                } else {
+                    None
+                };
+                if let Some(reason) = refusal {
+                    host.cast(ConnectionMessage::JoinLobbyFailed { name, reason })?;
+                    return Ok(());
+                }
+
                 let name_key = name.clone();
                 let name_for_lobby = name.clone();
-                let (actor, _) = Actor::spawn(
+                let host_ref = host.clone();
+                match Actor::spawn(
                     Some(name),
                     LobbyActor,
                     LobbyArguments {
                         server_ref: myself,
                         name: name_for_lobby,
-                        host_conn: host,
+                        map: state.map.clone(),
+                        host: LobbyMember {
+                            conn: host,
+                            name: host_name,
+                        },
                     },
                 )
                 .await
-                .expect("Failed to start lobby actor");
-                state.lobbies.insert(name_key, actor);
+                {
+                    Ok((actor, _)) => {
+                        state.lobbies.insert(name_key, actor);
+                    }
+                    // A lobby by that name that's closing can still hold it
+                    Err(e) => {
+                        warn!("Failed to start lobby {}: {}", name_key, e);
+                        host_ref.cast(ConnectionMessage::JoinLobbyFailed {
+                            name: name_key,
+                            reason: JoinLobbyError::NameTaken,
+                        })?;
+                    }
+                }
             }
+            ServerMessage::JoinLobby {
+                name,
+                conn,
+                player_name,
+            } => match state.lobbies.get(&name) {
+                Some(lobby) => lobby.cast(LobbyMessage::AddPlayer(LobbyMember {
+                    conn,
+                    name: player_name,
+                }))?,
+                None => conn.cast(ConnectionMessage::JoinLobbyFailed {
+                    name,
+                    reason: JoinLobbyError::NotFound,
+                })?,
+            },
             ServerMessage::LostConnection(id) => {
                 state.connection_actors.retain(|x| x.get_id() != id);
                 info!(
@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+
+use ractor::ActorId;
+
+// How many ticks of reports to keep around waiting for the other peers
+const REPORT_HISTORY_TICKS: i32 = 45 * 60;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HashSource {
+    Server,
+    Player(ActorId),
+}
+
+/// Compares the state hashes reported by every peer of a game, including the
+/// server's own sim, and finds the first tick where they disagree.
+pub struct DesyncDetector {
+    // Sparse by tick, every hash reported for that tick
+    reports: BTreeMap<i32, Vec<(HashSource, u64)>>,
+    first_desync: Option<i32>,
+}
+
+impl DesyncDetector {
+    pub fn new() -> DesyncDetector {
+        DesyncDetector {
+            reports: BTreeMap::new(),
+            first_desync: None,
+        }
+    }
+
+    /// Records a hash report, returning the tick if it reveals a desync
+    /// earlier than any seen so far.
+    pub fn report(&mut self, source: HashSource, tick: i32, hash: u64) -> Option<i32> {
+        if self.first_desync.is_some_and(|t| tick >= t) {
+            // Already diverged by then, nothing new to learn
+            return None;
+        }
+
+        let tick_reports = self.reports.entry(tick).or_default();
+        tick_reports.retain(|(s, _)| *s != source);
+        tick_reports.push((source, hash));
+        let mismatch = tick_reports.iter().any(|(_, h)| *h != hash);
+
+        self.prune();
+
+        if mismatch {
+            self.first_desync = Some(tick);
+            Some(tick)
+        } else {
+            None
+        }
+    }
+
+    fn prune(&mut self) {
+        if let Some(&latest_tick) = self.reports.keys().next_back() {
+            self.reports = self
+                .reports
+                .split_off(&(latest_tick - REPORT_HISTORY_TICKS));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: HashSource = HashSource::Player(ActorId::Local(1));
+    const BOB: HashSource = HashSource::Player(ActorId::Local(2));
+
+    #[test]
+    fn agreeing_hashes_are_fine() {
+        let mut detector = DesyncDetector::new();
+        assert_eq!(detector.report(HashSource::Server, 30, 7), None);
+        assert_eq!(detector.report(ALICE, 30, 7), None);
+        assert_eq!(detector.report(BOB, 30, 7), None);
+    }
+
+    #[test]
+    fn finds_the_first_mismatch() {
+        let mut detector = DesyncDetector::new();
+        detector.report(HashSource::Server, 30, 7);
+        detector.report(HashSource::Server, 60, 8);
+        assert_eq!(detector.report(ALICE, 60, 9), Some(60));
+        // Already known to have diverged by then
+        assert_eq!(detector.report(BOB, 90, 1), None);
+        // But a slow peer can reveal it happened earlier
+        assert_eq!(detector.report(BOB, 30, 6), Some(30));
+    }
+
+    #[test]
+    fn a_peer_can_correct_its_report() {
+        let mut detector = DesyncDetector::new();
+        detector.report(ALICE, 30, 1);
+        detector.report(ALICE, 30, 2);
+        assert_eq!(detector.report(BOB, 30, 2), None);
+    }
+
+    #[test]
+    fn old_reports_are_forgotten() {
+        let mut detector = DesyncDetector::new();
+        detector.report(HashSource::Server, 30, 7);
+        detector.report(HashSource::Server, 30 + REPORT_HISTORY_TICKS + 1, 8);
+        // Nothing left for tick 30 to disagree with
+        assert_eq!(detector.report(ALICE, 30, 9), None);
+    }
+}
@@ -1,19 +1,34 @@
 mod actors;
+mod desync;
+mod validation;
 
+use std::path::Path;
+
+use cm_sim::map::Map;
 use ractor::Actor;
 use tracing::info;
 use tracing_subscriber;
 
-use crate::actors::server::ServerActor;
+use crate::actors::server::{ServerActor, ServerArguments};
 
 #[tokio::main]
 async fn main() {
     tracing::subscriber::set_global_default(tracing_subscriber::FmtSubscriber::builder().finish())
         .unwrap();
 
+    // The map to play on can be given as the first argument
+    let map = match std::env::args().nth(1) {
+        Some(path) => Map::load(Path::new(&path)).expect("Failed to load map"),
+        None => Map::default(),
+    };
+
     info!("Starting server!");
-    let (_actor, actor_handle) = Actor::spawn(Some("Server".to_string()), ServerActor, ())
-        .await
-        .expect("Server actor failed to start");
+    let (_actor, actor_handle) = Actor::spawn(
+        Some("Server".to_string()),
+        ServerActor,
+        ServerArguments { map },
+    )
+    .await
+    .expect("Server actor failed to start");
     let _ = actor_handle.await;
 }
@@ -0,0 +1,112 @@
+use std::{collections::HashMap, fmt};
+
+use cm_shared_data::{valid_speed, Input, InputType};
+use cm_sim::{map::Map, simulation::SimSnapshot};
+
+#[derive(Debug, Copy, Clone)]
+pub enum Rejection {
+    PastTick { for_tick: i32, current_tick: i32 },
+    WrongPlayer { player_id: i32 },
+    NotOwner { circle_id: i64 },
+    OutOfBounds { x: f32, y: f32 },
+    BadSpeed { speed: f32 },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::PastTick {
+                for_tick,
+                current_tick,
+            } => write!(
+                f,
+                "for tick {} but the sim is already on {}",
+                for_tick, current_tick
+            ),
+            Rejection::WrongPlayer { player_id } => {
+                write!(f, "claims to be from player {}", player_id)
+            }
+            Rejection::NotOwner { circle_id } => {
+                write!(f, "circle {} isn't theirs", circle_id)
+            }
+            Rejection::OutOfBounds { x, y } => write!(f, "({}, {}) is off the map", x, y),
+            Rejection::BadSpeed { speed } => write!(f, "can't run at {}x speed", speed),
+        }
+    }
+}
+
+/// Checks inputs against the server's sim before they're relayed to anyone,
+/// keeping count of how many each player has had dropped.
+pub struct InputValidator {
+    rejections: HashMap<i32, u32>,
+}
+
+impl InputValidator {
+    pub fn new() -> InputValidator {
+        InputValidator {
+            rejections: HashMap::new(),
+        }
+    }
+
+    /// Checks an input sent by `sender`, counting it against them if it
+    /// has to be dropped.
+    pub fn validate(
+        &mut self,
+        sender: i32,
+        input: &Input,
+        snapshot: &SimSnapshot,
+    ) -> Result<(), Rejection> {
+        let result = check(sender, input, snapshot);
+        if result.is_err() {
+            *self.rejections.entry(sender).or_default() += 1;
+        }
+        result
+    }
+
+    pub fn rejections(&self, player_id: i32) -> u32 {
+        self.rejections.get(&player_id).copied().unwrap_or(0)
+    }
+}
+
+fn check(sender: i32, input: &Input, snapshot: &SimSnapshot) -> Result<(), Rejection> {
+    if input.player_id != sender {
+        return Err(Rejection::WrongPlayer {
+            player_id: input.player_id,
+        });
+    }
+    if input.for_tick < snapshot.tick {
+        return Err(Rejection::PastTick {
+            for_tick: input.for_tick,
+            current_tick: snapshot.tick,
+        });
+    }
+    match input.input_type {
+        InputType::CreateCircle { x, y } => check_position(snapshot.game.map(), x, y),
+        InputType::SetDestination { circle_id, x, y } => {
+            if !snapshot.game.circle_owned_by(circle_id, sender) {
+                return Err(Rejection::NotOwner { circle_id });
+            }
+            check_position(snapshot.game.map(), x, y)
+        }
+        InputType::Pause | InputType::Resume => Ok(()),
+        InputType::SetSpeed { speed } => {
+            if valid_speed(speed) {
+                Ok(())
+            } else {
+                Err(Rejection::BadSpeed { speed })
+            }
+        }
+    }
+}
+
+fn check_position(map: &Map, x: f32, y: f32) -> Result<(), Rejection> {
+    let (min, max) = map.bounds();
+    // NaN fails both comparisons so it's caught here too
+    if (min.x.to_f32()..=max.x.to_f32()).contains(&x)
+        && (min.y.to_f32()..=max.y.to_f32()).contains(&y)
+    {
+        Ok(())
+    } else {
+        Err(Rejection::OutOfBounds { x, y })
+    }
+}
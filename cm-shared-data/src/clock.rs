@@ -0,0 +1,154 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+// How many of the latest pings to estimate from
+const SAMPLE_WINDOW: usize = 16;
+
+#[derive(Debug, Copy, Clone)]
+struct Sample {
+    rtt: Duration,
+    // Server clock minus ours, in nanoseconds
+    offset: i64,
+}
+
+/// Estimates the server's clock offset and the round trip time from pings,
+/// NTP style. The ping with the quickest round trip is trusted for the
+/// offset since it had the least room for the trip there and back to be
+/// lopsided.
+#[derive(Debug, Default, Clone)]
+pub struct ClockSync {
+    samples: VecDeque<Sample>,
+}
+
+impl ClockSync {
+    pub fn new() -> ClockSync {
+        ClockSync::default()
+    }
+
+    /// Records a ping we sent at `sent_at`, that the server answered at
+    /// `server_time` by its clock, and whose answer we got at `received_at`
+    pub fn record(
+        &mut self,
+        sent_at: SystemTime,
+        server_time: SystemTime,
+        received_at: SystemTime,
+    ) {
+        let rtt = match received_at.duration_since(sent_at) {
+            Ok(rtt) => rtt,
+            // Our clock jumped backwards mid ping, nothing to learn from it
+            Err(_) => return,
+        };
+        // Assume the server answered halfway through the round trip
+        let offset = signed_nanos(server_time, sent_at + rtt / 2);
+
+        self.samples.push_back(Sample { rtt, offset });
+        if self.samples.len() > SAMPLE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// How many nanoseconds the server's clock is ahead of ours, negative if
+    /// it's behind
+    pub fn offset(&self) -> Option<i64> {
+        self.samples.iter().min_by_key(|s| s.rtt).map(|s| s.offset)
+    }
+
+    /// The average round trip time over the recent pings
+    pub fn rtt(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let total: Duration = self.samples.iter().map(|s| s.rtt).sum();
+        Some(total / self.samples.len() as u32)
+    }
+
+    /// The spread between the quickest and slowest recent round trips
+    pub fn jitter(&self) -> Option<Duration> {
+        let min = self.samples.iter().map(|s| s.rtt).min()?;
+        let max = self.samples.iter().map(|s| s.rtt).max()?;
+        Some(max - min)
+    }
+
+    /// Converts a time by the server's clock to ours, left as is until
+    /// there's been a ping
+    pub fn to_local(&self, server_time: SystemTime) -> SystemTime {
+        match self.offset() {
+            Some(offset) if offset >= 0 => server_time - Duration::from_nanos(offset as u64),
+            Some(offset) => server_time + Duration::from_nanos(offset.unsigned_abs()),
+            None => server_time,
+        }
+    }
+}
+
+/// `a - b` in nanoseconds
+fn signed_nanos(a: SystemTime, b: SystemTime) -> i64 {
+    match a.duration_since(b) {
+        Ok(d) => d.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn nothing_known_before_a_ping() {
+        let clock = ClockSync::new();
+        assert_eq!(clock.offset(), None);
+        assert_eq!(clock.rtt(), None);
+        assert_eq!(clock.jitter(), None);
+        assert_eq!(clock.to_local(at(1000)), at(1000));
+    }
+
+    #[test]
+    fn offset_from_a_symmetric_ping() {
+        let mut clock = ClockSync::new();
+        // Server is 500ms ahead, 20ms each way
+        clock.record(at(1000), at(1520), at(1040));
+        assert_eq!(clock.offset(), Some(500_000_000));
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(40)));
+        assert_eq!(clock.to_local(at(2500)), at(2000));
+
+        let mut clock = ClockSync::new();
+        // And 500ms behind
+        clock.record(at(1000), at(520), at(1040));
+        assert_eq!(clock.offset(), Some(-500_000_000));
+        assert_eq!(clock.to_local(at(1500)), at(2000));
+    }
+
+    #[test]
+    fn quickest_ping_decides_the_offset() {
+        let mut clock = ClockSync::new();
+        clock.record(at(1000), at(1600), at(1100));
+        clock.record(at(2000), at(2510), at(2020));
+        clock.record(at(3000), at(3400), at(3200));
+        assert_eq!(clock.offset(), Some(500_000_000));
+        assert_eq!(clock.rtt(), Some(Duration::from_nanos(106_666_666)));
+        assert_eq!(clock.jitter(), Some(Duration::from_millis(180)));
+    }
+
+    #[test]
+    fn only_recent_pings_count() {
+        let mut clock = ClockSync::new();
+        clock.record(at(0), at(0), at(1));
+        for i in 1..=SAMPLE_WINDOW as u64 {
+            clock.record(at(i * 1000), at(i * 1000 + 5), at(i * 1000 + 10));
+        }
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(10)));
+        assert_eq!(clock.offset(), Some(0));
+    }
+
+    #[test]
+    fn ignores_answers_received_before_the_ping_was_sent() {
+        let mut clock = ClockSync::new();
+        clock.record(at(1000), at(1000), at(900));
+        assert_eq!(clock.offset(), None);
+    }
+}
@@ -1,28 +1,100 @@
+pub mod clock;
+
 use anyhow::Result;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::time::SystemTime;
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
 
 /// Messages from server to client
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerNetworkMessage {
     LobbyMessage(ServerLobbyMessage),
-    InputMessage(Input),
+    TickBundle(TickBundle),
+    /// The state hashes reported for `tick` didn't all agree, this is the
+    /// earliest tick known to have diverged.
+    DesyncDetected {
+        tick: i32,
+    },
+    /// Answers a `Ping`, `server_time` is when the server handled it by
+    /// its clock
+    Pong {
+        client_sent_at: SystemTime,
+        server_time: SystemTime,
+    },
+    /// The fewest ticks ahead the whole lobby should schedule inputs, raised
+    /// when someone's latency grows
+    InputDelayChanged {
+        ticks: i32,
+    },
 }
 
 /// Just a bunch of static utility functions for creating serialized message bytes
 impl ServerNetworkMessage {
-    pub fn lobby_joined(name: String, other_players: Vec<String>) -> Result<Vec<u8>> {
+    pub fn lobby_joined(
+        name: String,
+        player_id: i32,
+        other_players: Vec<LobbyPlayer>,
+    ) -> Result<Vec<u8>> {
         serialize_server_message(&Self::LobbyMessage(ServerLobbyMessage::LobbyJoined {
             name,
+            player_id,
             other_players,
         }))
     }
 
-    pub fn synchronized_game_start(start_at: SystemTime) -> Result<Vec<u8>> {
+    pub fn lobby_roster_changed(players: Vec<LobbyPlayer>) -> Result<Vec<u8>> {
+        serialize_server_message(&Self::LobbyMessage(
+            ServerLobbyMessage::LobbyRosterChanged { players },
+        ))
+    }
+
+    pub fn lobby_closed(name: String) -> Result<Vec<u8>> {
+        serialize_server_message(&Self::LobbyMessage(ServerLobbyMessage::LobbyClosed {
+            name,
+        }))
+    }
+
+    pub fn join_lobby_failed(name: String, reason: JoinLobbyError) -> Result<Vec<u8>> {
+        serialize_server_message(&Self::LobbyMessage(ServerLobbyMessage::JoinLobbyFailed {
+            name,
+            reason,
+        }))
+    }
+
+    pub fn synchronized_game_start(
+        start_at: SystemTime,
+        players: Vec<i32>,
+        map: String,
+    ) -> Result<Vec<u8>> {
         serialize_server_message(&Self::LobbyMessage(
-            ServerLobbyMessage::SynchronizedGameStart { start_at },
+            ServerLobbyMessage::SynchronizedGameStart {
+                start_at,
+                players,
+                map,
+            },
         ))
     }
+
+    pub fn tick_bundle(bundle: TickBundle) -> Result<Vec<u8>> {
+        serialize_server_message(&Self::TickBundle(bundle))
+    }
+
+    pub fn desync_detected(tick: i32) -> Result<Vec<u8>> {
+        serialize_server_message(&Self::DesyncDetected { tick })
+    }
+
+    pub fn input_delay_changed(ticks: i32) -> Result<Vec<u8>> {
+        serialize_server_message(&Self::InputDelayChanged { ticks })
+    }
+
+    pub fn pong(client_sent_at: SystemTime, server_time: SystemTime) -> Result<Vec<u8>> {
+        serialize_server_message(&Self::Pong {
+            client_sent_at,
+            server_time,
+        })
+    }
 }
 
 /// Messages from client to server
@@ -30,17 +102,33 @@ impl ServerNetworkMessage {
 pub enum ClientNetworkMessage {
     LobbyMessage(ClientLobbyMessage),
     InputMessage(Input),
+    /// The client's game state checksum after `tick`
+    StateHash {
+        // The `start_at` of the game it's for, so hashes from before a
+        // restart can be told apart
+        start_at: SystemTime,
+        tick: i32,
+        hash: u64,
+    },
+    /// Asks the server for its time so we can work out our clock offset
+    Ping {
+        sent_at: SystemTime,
+    },
 }
 
 impl ClientNetworkMessage {
-    pub fn create_lobby(name: String) -> Result<Vec<u8>> {
+    pub fn create_lobby(name: String, player_name: String) -> Result<Vec<u8>> {
         serialize_client_message(&Self::LobbyMessage(ClientLobbyMessage::CreateLobby {
             name,
+            player_name,
         }))
     }
 
-    pub fn join_lobby(name: String) -> Result<Vec<u8>> {
-        serialize_client_message(&Self::LobbyMessage(ClientLobbyMessage::JoinLobby { name }))
+    pub fn join_lobby(name: String, player_name: String) -> Result<Vec<u8>> {
+        serialize_client_message(&Self::LobbyMessage(ClientLobbyMessage::JoinLobby {
+            name,
+            player_name,
+        }))
     }
 
     pub fn request_start_game() -> Result<Vec<u8>> {
@@ -50,23 +138,128 @@ impl ClientNetworkMessage {
     pub fn input(input: Input) -> Result<Vec<u8>> {
         serialize_client_message(&Self::InputMessage(input))
     }
+
+    pub fn state_hash(start_at: SystemTime, tick: i32, hash: u64) -> Result<Vec<u8>> {
+        serialize_client_message(&Self::StateHash {
+            start_at,
+            tick,
+            hash,
+        })
+    }
+
+    pub fn ping(sent_at: SystemTime) -> Result<Vec<u8>> {
+        serialize_client_message(&Self::Ping { sent_at })
+    }
+}
+
+/// How often, in ticks, peers report their state hash for desync detection
+pub const STATE_HASH_INTERVAL: i32 = 45;
+
+/// Roughly 45hz
+pub const TICK_DURATION: Duration = Duration::from_millis(22);
+
+/// The range of speeds a sim can be run at. Slow enough to watch closely,
+/// fast enough for testing long scenarios without the sims falling behind.
+pub const MIN_SPEED: f32 = 0.125;
+pub const MAX_SPEED: f32 = 8.0;
+
+/// Whether a sim can be run at `speed`, NaN never can
+pub fn valid_speed(speed: f32) -> bool {
+    (MIN_SPEED..=MAX_SPEED).contains(&speed)
+}
+
+/// How far ahead of the server sim input bundles are closed and sent out,
+/// giving them time to reach every client before they're needed. Three ticks
+/// at normal speed, more when the game's sped up.
+pub const BUNDLE_LEAD: Duration = Duration::from_millis(66);
+
+/// `BUNDLE_LEAD` in ticks of a sim running at `speed`
+pub fn bundle_lead_ticks(speed: f32) -> i32 {
+    ticks_in(BUNDLE_LEAD, speed)
+}
+
+/// How many ticks ahead of the current one to schedule inputs so they reach
+/// the server before their tick's bundle is closed, for a sim running at
+/// `speed`
+pub fn input_delay_ticks(rtt: Duration, jitter: Duration, speed: f32) -> i32 {
+    let one_way = rtt / 2 + jitter;
+    bundle_lead_ticks(speed) + ticks_in(one_way, speed) + 1
+}
+
+/// How many ticks a sim running at `speed` gets through in `duration`,
+/// rounded up
+fn ticks_in(duration: Duration, speed: f32) -> i32 {
+    let ticks = duration.as_nanos() as f64 * speed as f64 / TICK_DURATION.as_nanos() as f64;
+    ticks.ceil() as i32
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerLobbyMessage {
     LobbyJoined {
         name: String,
-        other_players: Vec<String>,
+        // The id the server assigned us, only inputs for it are accepted
+        player_id: i32,
+        other_players: Vec<LobbyPlayer>,
+    },
+    JoinLobbyFailed {
+        name: String,
+        reason: JoinLobbyError,
+    },
+    // Everyone in the lobby in player id order, the host first
+    LobbyRosterChanged {
+        players: Vec<LobbyPlayer>,
+    },
+    // Someone left that the lobby can't go on without, we're out of it
+    LobbyClosed {
+        name: String,
     },
     SynchronizedGameStart {
         start_at: SystemTime,
+        // The lockstep players whose input every tick waits on
+        players: Vec<i32>,
+        // The map file to play on, sent whole so every peer parses the same
+        // thing
+        map: String,
     },
 }
 
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub enum JoinLobbyError {
+    NotFound,
+    Full,
+    GameStarted,
+    // Only when creating a lobby
+    NameTaken,
+    BadName,
+}
+
+impl fmt::Display for JoinLobbyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinLobbyError::NotFound => write!(f, "Lobby does not exist"),
+            JoinLobbyError::Full => write!(f, "Lobby is full"),
+            JoinLobbyError::GameStarted => write!(f, "Game has already started"),
+            JoinLobbyError::NameTaken => write!(f, "A lobby with that name already exists"),
+            JoinLobbyError::BadName => {
+                write!(
+                    f,
+                    "Lobby names can't be blank or contain control characters"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LobbyPlayer {
+    pub player_id: i32,
+    pub name: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ClientLobbyMessage {
-    CreateLobby { name: String },
-    JoinLobby { name: String },
+    CreateLobby { name: String, player_name: String },
+    JoinLobby { name: String, player_name: String },
     RequestStartGame,
 }
 
@@ -74,6 +267,12 @@ pub enum ClientLobbyMessage {
 pub enum InputType {
     CreateCircle { x: f32, y: f32 },
     SetDestination { circle_id: i64, x: f32, y: f32 },
+    // Stops the game moving until resumed, ticks keep counting so inputs
+    // still get processed
+    Pause,
+    Resume,
+    // Multiplier on how quickly ticks are run against the wall clock
+    SetSpeed { speed: f32 },
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -83,6 +282,24 @@ pub struct Input {
     pub input_type: InputType,
 }
 
+/// Everything one player did on one tick, possibly nothing. In lockstep a
+/// tick can't be processed until every player's bundle for it has arrived.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputBundle {
+    pub for_tick: i32,
+    pub player_id: i32,
+    pub inputs: Vec<Input>,
+}
+
+/// The server's final word on a tick, one bundle for every player in player
+/// id order. Every peer applies the same bundles in the same order so input
+/// ordering can't differ between machines.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TickBundle {
+    pub tick: i32,
+    pub bundles: Vec<InputBundle>,
+}
+
 pub fn serialize_client_message(msg: &ClientNetworkMessage) -> Result<Vec<u8>> {
     let bytes = bincode::serialize(msg)?;
     Ok(bytes)
@@ -94,7 +311,7 @@ pub fn serialize_server_message(msg: &ServerNetworkMessage) -> Result<Vec<u8>> {
 }
 
 // FIXME: Determine the actual max message size once we've figured out what all the messages will be
-const MAX_MESSAGE_SIZE: usize = 1024;
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
 
 #[tracing::instrument]
 pub async fn read_message<T: DeserializeOwned>(conn: &quinn::Connection) -> Result<T> {
@@ -1,54 +1,310 @@
 use std::{
-    collections::HashMap,
+    iter,
+    path::PathBuf,
     time::{Duration, SystemTime},
 };
 
-use cm_shared_data::Input;
-use queues::{IsQueue, Queue};
-use ractor::{async_trait, Actor, ActorProcessingErr, ActorRef};
-use tokio::sync::watch;
+use cm_shared_data::{Input, InputBundle, InputType, TickBundle, STATE_HASH_INTERVAL};
+use ractor::{async_trait, Actor, ActorProcessingErr, ActorRef, MessagingErr};
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    game::{Game, GameEvent},
+    map::Map,
+    replay::{ReplayHeader, ReplayWriter},
+    simulation::{LateInput, LateInputPolicy, SimSnapshot, Simulation},
+};
 
-use crate::game::Game;
+// The most ticks processed in one go when catching up, so a long hitch
+// doesn't hold up handling input
+const MAX_CATCH_UP_TICKS: i32 = 8;
 
 pub enum SimMessage {
     Tick,
     SendInput(Input),
+    SendInputBundle(InputBundle),
+    SendTickBundle(TickBundle),
     StartAt(SystemTime),
     Start,
+    // Scheduled as inputs on the next tick, only for sims nobody else has to
+    // agree with. Networked games send the inputs through the server.
+    Pause,
+    Resume,
+    SetSpeed(f32),
+    // Stops ticking and shuts the actor down, dropping the game state
+    // sender so anyone watching it knows the sim is gone
+    Stop,
+}
+
+/// Things that happened in the sim that whoever's running it may want to
+/// know about
+#[derive(Debug, Clone)]
+pub enum SimEvent {
+    LateInput(LateInput),
+    // The replay couldn't be written, the sim carries on without recording
+    ReplayFailed(String),
+    // The state hash every `STATE_HASH_INTERVAL` ticks, sent from the tick
+    // loop so none are missed however many ticks are processed at once
+    StateHash { tick: i32, hash: u64 },
+    Game { tick: i32, event: GameEvent },
+}
+
+/// When each tick is due, tick `n` is due `n - tick` tick durations, sped up
+/// by `speed`, after `at`. It's rebased on the tick the sim's speed changes
+/// so every peer keeps agreeing on when each tick is due.
+#[derive(Debug, Copy, Clone)]
+struct TickSchedule {
+    tick: i32,
+    at: SystemTime,
+    speed: f32,
+}
+
+impl TickSchedule {
+    fn tick_duration(&self, base: Duration) -> Duration {
+        base.div_f32(self.speed).max(Duration::from_nanos(1))
+    }
+
+    /// How many ticks should have been processed by `now`
+    fn due_ticks(&self, base: Duration, now: SystemTime) -> i32 {
+        match now.duration_since(self.at) {
+            Ok(elapsed) => {
+                let ticks = elapsed.as_nanos() / self.tick_duration(base).as_nanos();
+                self.tick + ticks as i32 + 1
+            }
+            Err(_) => self.tick,
+        }
+    }
+
+    fn due_at(&self, base: Duration, tick: i32) -> SystemTime {
+        self.at + self.tick_duration(base) * (tick - self.tick) as u32
+    }
+}
+
+/// A replay that can't be created until the sim starts, its header needs
+/// the start time
+struct PendingReplay {
+    path: PathBuf,
+    // Everything buffered before then
+    inputs: Vec<Input>,
 }
 
 pub struct SimState {
-    game: Game,
-    game_state_sender: watch::Sender<(i32, Game)>,
-    // Hashmap as a sparse array indexed by tick
-    input_buffer: HashMap<i32, Queue<Input>>,
-    current_tick: i32,
+    sim: Simulation,
+    game_state_sender: watch::Sender<SimSnapshot>,
     minimum_tick_duration: Duration,
+    pending_replay: Option<PendingReplay>,
+    replay: Option<ReplayWriter>,
+    event_sender: Option<mpsc::UnboundedSender<SimEvent>>,
+    // None until the sim's been started
+    schedule: Option<TickSchedule>,
+    // The next scheduled Tick, there's only ever one pending
+    tick_timer: Option<JoinHandle<Result<(), MessagingErr<SimMessage>>>>,
+    // Whether the last tick was held waiting on input
+    stalled: bool,
 }
 
 impl SimState {
-    fn tick(&mut self) {
-        // Process all buffered input for this tick
-        if let Some(ref mut tick_buffer) = self.input_buffer.remove(&self.current_tick) {
-            while let Ok(input) = tick_buffer.remove() {
-                self.game.handle_input(input);
+    fn start(&mut self, myself: &ActorRef<SimMessage>, at: SystemTime) {
+        if let Some(pending) = self.pending_replay.take() {
+            self.create_replay(pending, at);
+        }
+        self.schedule = Some(TickSchedule {
+            tick: 0,
+            at,
+            speed: self.sim.speed(),
+        });
+        // If the start is already behind us the first tick is due right away
+        // and the rest are caught up on from there
+        let delay = at
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        self.schedule_tick(myself, delay);
+    }
+
+    /// How many ticks should have been processed by `now`
+    fn due_ticks(&self, now: SystemTime) -> i32 {
+        match self.schedule {
+            Some(schedule) => schedule.due_ticks(self.minimum_tick_duration, now),
+            None => 0,
+        }
+    }
+
+    /// Rebases the schedule if the tick just processed changed the speed
+    fn follow_speed(&mut self) {
+        if let Some(schedule) = self.schedule {
+            if schedule.speed != self.sim.speed() {
+                let tick = self.sim.current_tick();
+                self.schedule = Some(TickSchedule {
+                    tick,
+                    at: schedule.due_at(self.minimum_tick_duration, tick),
+                    speed: self.sim.speed(),
+                });
+            }
+        }
+    }
+
+    /// Processes every tick that's due by the wall clock, at most
+    /// `MAX_CATCH_UP_TICKS` of them, and schedules the next Tick.
+    fn tick(&mut self, myself: &ActorRef<SimMessage>) {
+        if self.schedule.is_none() {
+            return;
+        }
+        let now = SystemTime::now();
+
+        self.stalled = false;
+        let mut processed = 0;
+        while self.sim.current_tick() < self.due_ticks(now) && processed < MAX_CATCH_UP_TICKS {
+            match self.sim.advance(iter::empty()) {
+                Ok(events) => {
+                    // They happened on the tick that was just processed
+                    let tick = self.sim.current_tick() - 1;
+                    for event in events {
+                        self.send_event(SimEvent::Game { tick, event });
+                    }
+                    self.follow_speed();
+                    let mut snapshot = self.sim.snapshot();
+                    snapshot.tick_lag = self.due_ticks(now) - self.sim.current_tick();
+                    if snapshot.tick % STATE_HASH_INTERVAL == 0 {
+                        self.send_event(SimEvent::StateHash {
+                            tick: snapshot.tick,
+                            hash: snapshot.checksum,
+                        });
+                    }
+                    self.game_state_sender.send_replace(snapshot);
+                    processed += 1;
+                }
+                // Hold the tick until the missing input shows up, let anyone
+                // watching know who we're waiting on
+                Err(stalled) => {
+                    let tick_lag = self.due_ticks(now) - self.sim.current_tick();
+                    self.game_state_sender.send_if_modified(|snapshot| {
+                        if snapshot.waiting_on == stalled.missing_players
+                            && snapshot.tick_lag == tick_lag
+                        {
+                            false
+                        } else {
+                            snapshot.waiting_on = stalled.missing_players;
+                            snapshot.tick_lag = tick_lag;
+                            true
+                        }
+                    });
+                    self.stalled = true;
+                    break;
+                }
             }
         }
 
-        self.game.step();
-        self.current_tick += 1;
-        self.game_state_sender
-            .send_replace((self.current_tick, self.game.clone()));
+        if self.stalled {
+            // New input wakes us up sooner, this just keeps the lag current
+            self.schedule_tick(myself, self.minimum_tick_duration);
+        } else if let Some(schedule) = self.schedule {
+            // Straight back to it if there's still catching up to do
+            let next_due = schedule.due_at(self.minimum_tick_duration, self.sim.current_tick());
+            let delay = next_due.duration_since(now).unwrap_or(Duration::ZERO);
+            self.schedule_tick(myself, delay);
+        }
+    }
+
+    fn schedule_tick(&mut self, myself: &ActorRef<SimMessage>, delay: Duration) {
+        if let Some(timer) = self.tick_timer.take() {
+            timer.abort();
+        }
+        self.tick_timer = Some(myself.send_after(delay, || SimMessage::Tick));
+    }
+
+    /// Retries a stalled tick right away, the input it's waiting on may have
+    /// just shown up
+    fn wake(&mut self, myself: &ActorRef<SimMessage>) {
+        if self.stalled {
+            self.stalled = false;
+            self.schedule_tick(myself, Duration::ZERO);
+        }
     }
 
     fn buffer_input(&mut self, input: Input) {
-        // TODO: Check for < current_tick
-        if let Some(tick_buffer) = self.input_buffer.get_mut(&input.for_tick) {
-            tick_buffer.add(input).unwrap();
-        } else {
-            let mut queue = Queue::new();
-            queue.add(input).unwrap();
-            self.input_buffer.insert(input.for_tick, queue);
+        match self.sim.buffer_input(input) {
+            None => self.record_input(&input),
+            Some(late) => {
+                // Only record what the sim will actually process
+                if late.policy == LateInputPolicy::Reschedule {
+                    self.record_input(&Input {
+                        for_tick: late.current_tick,
+                        ..input
+                    });
+                }
+                self.send_event(SimEvent::LateInput(late));
+            }
+        }
+    }
+
+    /// Schedules a control input on the next tick to be processed
+    fn buffer_control_input(&mut self, input_type: InputType) {
+        self.buffer_input(Input {
+            for_tick: self.sim.current_tick(),
+            // Offline there's only ever player 0
+            player_id: 0,
+            input_type,
+        });
+    }
+
+    fn buffer_bundle(&mut self, mut bundle: InputBundle) {
+        // Inputs go in one at a time so each is recorded and checked for
+        // being late, what's left confirms the bundle
+        for input in std::mem::take(&mut bundle.inputs) {
+            self.buffer_input(input);
+        }
+        self.sim.buffer_bundle(bundle);
+    }
+
+    fn send_event(&mut self, event: SimEvent) {
+        if let Some(ref sender) = self.event_sender {
+            if sender.send(event).is_err() {
+                // Nobody's listening anymore
+                self.event_sender = None;
+            }
+        }
+    }
+
+    fn create_replay(&mut self, pending: PendingReplay, start_at: SystemTime) {
+        let header = ReplayHeader {
+            tick_duration: self.minimum_tick_duration,
+            recorded_at: SystemTime::now(),
+            start_at,
+            players: self.sim.players(),
+            map: self.sim.game().map().source().to_string(),
+        };
+        match ReplayWriter::create(&pending.path, &header) {
+            Ok(writer) => {
+                self.replay = Some(writer);
+                for input in pending.inputs {
+                    self.record_input(&input);
+                }
+            }
+            // Not being able to record is no reason not to play
+            Err(e) => self.send_event(SimEvent::ReplayFailed(format!(
+                "Failed to create replay {}: {:?}",
+                pending.path.display(),
+                e
+            ))),
+        }
+    }
+
+    fn record_input(&mut self, input: &Input) {
+        if let Some(ref mut pending) = self.pending_replay {
+            pending.inputs.push(*input);
+        }
+        if let Some(ref mut replay) = self.replay {
+            if let Err(e) = replay.record_input(input) {
+                // Losing the replay shouldn't take the game down with it
+                self.replay = None;
+                self.send_event(SimEvent::ReplayFailed(format!(
+                    "Failed to record input, stopping recording: {:?}",
+                    e
+                )));
+            }
         }
     }
 }
@@ -56,7 +312,17 @@ impl SimState {
 pub struct SimArguments {
     pub minimum_tick_duration: Duration,
     // A watch channel to publish game state to each tick
-    pub game_state_sender: watch::Sender<(i32, Game)>,
+    pub game_state_sender: watch::Sender<SimSnapshot>,
+    // Where to record a replay of every input, if anywhere
+    pub replay_path: Option<PathBuf>,
+    // Lockstep players whose input must arrive before each tick, with none
+    // the sim never waits
+    pub players: Vec<i32>,
+    // Every peer has to play on the same one
+    pub map: Map,
+    pub late_input_policy: LateInputPolicy,
+    // Where to send sim events, if anywhere
+    pub event_sender: Option<mpsc::UnboundedSender<SimEvent>>,
 }
 
 pub struct SimActor;
@@ -72,12 +338,25 @@ impl Actor for SimActor {
         _myself: ActorRef<Self::Msg>,
         arguments: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
+        let mut sim = Simulation::with_players(
+            Game::with_map(arguments.minimum_tick_duration, arguments.map),
+            arguments.players,
+        );
+        sim.set_late_input_policy(arguments.late_input_policy);
+
         Ok(SimState {
             game_state_sender: arguments.game_state_sender,
-            game: Game::new(arguments.minimum_tick_duration),
-            input_buffer: HashMap::new(),
-            current_tick: 0,
+            sim,
             minimum_tick_duration: arguments.minimum_tick_duration,
+            pending_replay: arguments.replay_path.map(|path| PendingReplay {
+                path,
+                inputs: vec![],
+            }),
+            replay: None,
+            event_sender: arguments.event_sender,
+            schedule: None,
+            tick_timer: None,
+            stalled: false,
         })
     }
 
@@ -89,19 +368,56 @@ impl Actor for SimActor {
     ) -> Result<(), ActorProcessingErr> {
         match message {
             SimMessage::Tick => {
-                state.tick();
+                state.tick(&myself);
             }
             SimMessage::SendInput(input) => {
                 state.buffer_input(input);
+                state.wake(&myself);
+            }
+            SimMessage::SendInputBundle(bundle) => {
+                state.buffer_bundle(bundle);
+                state.wake(&myself);
+            }
+            SimMessage::SendTickBundle(tick_bundle) => {
+                for bundle in tick_bundle.bundles {
+                    state.buffer_bundle(bundle);
+                }
+                state.wake(&myself);
             }
             SimMessage::StartAt(ts) => {
-                // TODO: Validate that argument is in the future
-                myself.send_after(ts.duration_since(SystemTime::now())?, || SimMessage::Start);
+                state.start(&myself, ts);
             }
             SimMessage::Start => {
-                myself.send_interval(state.minimum_tick_duration, || SimMessage::Tick);
+                state.start(&myself, SystemTime::now());
+            }
+            SimMessage::Pause => {
+                state.buffer_control_input(InputType::Pause);
+                state.wake(&myself);
+            }
+            SimMessage::Resume => {
+                state.buffer_control_input(InputType::Resume);
+                state.wake(&myself);
+            }
+            SimMessage::SetSpeed(speed) => {
+                state.buffer_control_input(InputType::SetSpeed { speed });
+                state.wake(&myself);
+            }
+            SimMessage::Stop => {
+                myself.stop(Some("Sim stopped".to_string()));
             }
         };
         Ok(())
     }
+
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        // However we stopped, don't leave a Tick pending for a dead actor
+        if let Some(timer) = state.tick_timer.take() {
+            timer.abort();
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+/// Anything kept in an `EntityStore`
+pub trait Entity {
+    fn id(&self) -> i64;
+}
+
+/// Entities packed densely for iterating, with an index by id for finding
+/// them. Removing swaps the last entity into the hole so both are O(1).
+///
+/// Iteration order only depends on the order entities were added and
+/// removed in, so it's the same on every peer. The id index is rebuilt from
+/// the entities and isn't part of the state.
+#[derive(Clone, Debug)]
+pub struct EntityStore<T> {
+    entities: Vec<T>,
+    index_by_id: HashMap<i64, usize>,
+}
+
+impl<T: Entity> EntityStore<T> {
+    pub fn new() -> EntityStore<T> {
+        EntityStore {
+            entities: Vec::new(),
+            index_by_id: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// Adds an entity, replacing any with the same id
+    pub fn insert(&mut self, entity: T) {
+        match self.index_by_id.get(&entity.id()) {
+            Some(&index) => self.entities[index] = entity,
+            None => {
+                self.index_by_id.insert(entity.id(), self.entities.len());
+                self.entities.push(entity);
+            }
+        }
+    }
+
+    /// Removes an entity, moving the last one into its place. Hands back
+    /// the removed entity and the index it was at.
+    pub fn remove(&mut self, id: i64) -> Option<(usize, T)> {
+        let index = self.index_by_id.remove(&id)?;
+        let entity = self.entities.swap_remove(index);
+        if let Some(moved) = self.entities.get(index) {
+            self.index_by_id.insert(moved.id(), index);
+        }
+        Some((index, entity))
+    }
+
+    pub fn get(&self, id: i64) -> Option<&T> {
+        self.index_of(id).map(|index| &self.entities[index])
+    }
+
+    pub fn get_mut(&mut self, id: i64) -> Option<&mut T> {
+        self.index_of(id).map(|index| &mut self.entities[index])
+    }
+
+    pub fn index_of(&self, id: i64) -> Option<usize> {
+        self.index_by_id.get(&id).copied()
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.entities
+    }
+
+    /// Entities can be changed in place but not swapped around, their ids
+    /// have to stay where they are
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.entities
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entities.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.entities.iter_mut()
+    }
+}
+
+impl<T: Entity> Default for EntityStore<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Thing {
+        id: i64,
+        name: &'static str,
+    }
+
+    impl Entity for Thing {
+        fn id(&self) -> i64 {
+            self.id
+        }
+    }
+
+    fn store(ids: &[i64]) -> EntityStore<Thing> {
+        let mut store = EntityStore::new();
+        for &id in ids {
+            store.insert(Thing { id, name: "thing" });
+        }
+        store
+    }
+
+    fn ids(store: &EntityStore<Thing>) -> Vec<i64> {
+        store.iter().map(|t| t.id).collect()
+    }
+
+    #[test]
+    fn keeps_insertion_order() {
+        let store = store(&[7, 3, 5]);
+        assert_eq!(ids(&store), vec![7, 3, 5]);
+        assert_eq!(store.index_of(3), Some(1));
+        assert_eq!(store.get(5).map(|t| t.id), Some(5));
+        assert_eq!(store.get(4), None);
+    }
+
+    #[test]
+    fn inserting_an_existing_id_replaces_it_in_place() {
+        let mut store = store(&[7, 3, 5]);
+        store.insert(Thing {
+            id: 3,
+            name: "replaced",
+        });
+        assert_eq!(ids(&store), vec![7, 3, 5]);
+        assert_eq!(store.get(3).unwrap().name, "replaced");
+    }
+
+    #[test]
+    fn removing_moves_the_last_into_the_hole() {
+        let mut store = store(&[7, 3, 5, 9]);
+        let (index, removed) = store.remove(3).unwrap();
+        assert_eq!((index, removed.id), (1, 3));
+        assert_eq!(ids(&store), vec![7, 9, 5]);
+        assert_eq!(store.index_of(9), Some(1));
+        assert_eq!(store.get(3), None);
+        assert!(store.remove(3).is_none());
+
+        // Removing the last one has nothing to move
+        let (index, removed) = store.remove(5).unwrap();
+        assert_eq!((index, removed.id), (2, 5));
+        assert_eq!(ids(&store), vec![7, 9]);
+        assert_eq!(store.index_of(7), Some(0));
+        assert_eq!(store.index_of(9), Some(1));
+    }
+}
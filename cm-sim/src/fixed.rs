@@ -0,0 +1,283 @@
+use std::{
+    fmt,
+    ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign},
+    time::Duration,
+};
+
+const FRAC_BITS: u32 = 16;
+const ONE_RAW: i64 = 1 << FRAC_BITS;
+
+/// A signed fixed point number with 16 fractional bits.
+///
+/// All simulation math goes through this type so that every machine computes
+/// bit-identical results regardless of compiler, CPU or optimization level.
+/// Floats only appear at the edges, when converting player input in and when
+/// handing positions to the renderer.
+#[derive(Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(ONE_RAW);
+
+    pub const fn from_raw(raw: i64) -> Fixed {
+        Fixed(raw)
+    }
+
+    pub const fn raw(self) -> i64 {
+        self.0
+    }
+
+    pub const fn from_int(value: i32) -> Fixed {
+        Fixed((value as i64) << FRAC_BITS)
+    }
+
+    /// Scaling by a power of two is exact for floats and `as` saturates
+    /// (NaN becomes 0), so this conversion is deterministic everywhere.
+    pub fn from_f32(value: f32) -> Fixed {
+        Fixed((value * ONE_RAW as f32) as i64)
+    }
+
+    /// Lossy, only meant for presenting sim state (rendering, logging).
+    pub fn to_f32(self) -> f32 {
+        (self.0 as f64 / ONE_RAW as f64) as f32
+    }
+
+    /// A duration in seconds
+    pub fn from_duration(duration: Duration) -> Fixed {
+        let raw = (duration.as_nanos() << FRAC_BITS) / 1_000_000_000;
+        Fixed(i64::try_from(raw).unwrap_or(i64::MAX))
+    }
+
+    pub fn abs(self) -> Fixed {
+        Fixed(self.0.abs())
+    }
+
+    /// Square root, negative numbers are treated as zero
+    pub fn sqrt(self) -> Fixed {
+        if self.0 <= 0 {
+            return Fixed::ZERO;
+        }
+        // sqrt(raw / 2^16) * 2^16 == sqrt(raw * 2^16)
+        Fixed(isqrt((self.0 as u128) << FRAC_BITS) as i64)
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+    fn add(self, rhs: Fixed) -> Fixed {
+        Fixed(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, rhs: Fixed) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+    fn sub(self, rhs: Fixed) -> Fixed {
+        Fixed(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, rhs: Fixed) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+    fn mul(self, rhs: Fixed) -> Fixed {
+        Fixed(((self.0 as i128 * rhs.0 as i128) >> FRAC_BITS) as i64)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+    fn div(self, rhs: Fixed) -> Fixed {
+        Fixed((((self.0 as i128) << FRAC_BITS) / rhs.0 as i128) as i64)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+    fn neg(self) -> Fixed {
+        Fixed(-self.0)
+    }
+}
+
+impl fmt::Debug for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0 as f64 / ONE_RAW as f64)
+    }
+}
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A 2d vector (or point) in fixed point map units
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Hash)]
+pub struct FixedVec2 {
+    pub x: Fixed,
+    pub y: Fixed,
+}
+
+impl FixedVec2 {
+    pub const ZERO: FixedVec2 = FixedVec2 {
+        x: Fixed::ZERO,
+        y: Fixed::ZERO,
+    };
+
+    pub const fn new(x: Fixed, y: Fixed) -> FixedVec2 {
+        FixedVec2 { x, y }
+    }
+
+    pub fn from_f32(x: f32, y: f32) -> FixedVec2 {
+        FixedVec2::new(Fixed::from_f32(x), Fixed::from_f32(y))
+    }
+
+    pub fn dot(self, rhs: FixedVec2) -> Fixed {
+        self.x * rhs.x + self.y * rhs.y
+    }
+
+    pub fn length(self) -> Fixed {
+        // Work on the raw values in u128 so large vectors can't overflow
+        let x = self.x.raw().unsigned_abs() as u128;
+        let y = self.y.raw().unsigned_abs() as u128;
+        Fixed::from_raw(isqrt(x * x + y * y) as i64)
+    }
+
+    pub fn scale(self, factor: Fixed) -> FixedVec2 {
+        FixedVec2::new(self.x * factor, self.y * factor)
+    }
+
+    /// Unit vector in the same direction, the zero vector has no direction
+    /// so it stays zero.
+    pub fn normalize(self) -> FixedVec2 {
+        let length = self.length();
+        if length == Fixed::ZERO {
+            return FixedVec2::ZERO;
+        }
+        FixedVec2::new(self.x / length, self.y / length)
+    }
+}
+
+impl Add for FixedVec2 {
+    type Output = FixedVec2;
+    fn add(self, rhs: FixedVec2) -> FixedVec2 {
+        FixedVec2::new(self.x + rhs.x, self.y + rhs.y)
+    }
+}
+
+impl AddAssign for FixedVec2 {
+    fn add_assign(&mut self, rhs: FixedVec2) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for FixedVec2 {
+    type Output = FixedVec2;
+    fn sub(self, rhs: FixedVec2) -> FixedVec2 {
+        FixedVec2::new(self.x - rhs.x, self.y - rhs.y)
+    }
+}
+
+impl SubAssign for FixedVec2 {
+    fn sub_assign(&mut self, rhs: FixedVec2) {
+        *self = *self - rhs;
+    }
+}
+
+impl Neg for FixedVec2 {
+    type Output = FixedVec2;
+    fn neg(self) -> FixedVec2 {
+        FixedVec2::new(-self.x, -self.y)
+    }
+}
+
+// Integer square root, rounded down
+fn isqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    let mut result = 0u128;
+    let mut bit = 1u128 << ((127 - n.leading_zeros()) & !1);
+    let mut n = n;
+    while bit != 0 {
+        if n >= result + bit {
+            n -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isqrt_rounds_down() {
+        for n in 0..1000u128 {
+            let root = isqrt(n);
+            assert!(
+                root * root <= n && (root + 1) * (root + 1) > n,
+                "isqrt({})",
+                n
+            );
+        }
+        assert_eq!(isqrt(u128::MAX), u64::MAX as u128);
+    }
+
+    #[test]
+    fn sqrt() {
+        assert_eq!(Fixed::from_int(16).sqrt(), Fixed::from_int(4));
+        assert_eq!(Fixed::from_f32(0.25).sqrt(), Fixed::from_f32(0.5));
+        assert_eq!(Fixed::from_int(-4).sqrt(), Fixed::ZERO);
+    }
+
+    #[test]
+    fn from_duration() {
+        assert_eq!(
+            Fixed::from_duration(Duration::from_secs(3)),
+            Fixed::from_int(3)
+        );
+        assert_eq!(
+            Fixed::from_duration(Duration::from_millis(250)),
+            Fixed::from_f32(0.25)
+        );
+        assert_eq!(
+            Fixed::from_duration(Duration::MAX),
+            Fixed::from_raw(i64::MAX)
+        );
+    }
+
+    #[test]
+    fn from_f32() {
+        assert_eq!(Fixed::from_f32(1.5), Fixed::from_raw(ONE_RAW * 3 / 2));
+        assert_eq!(Fixed::from_f32(-2.0), Fixed::from_int(-2));
+        assert_eq!(Fixed::from_f32(f32::NAN), Fixed::ZERO);
+        assert_eq!(Fixed::from_f32(f32::INFINITY), Fixed::from_raw(i64::MAX));
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(FixedVec2::ZERO.normalize(), FixedVec2::ZERO);
+        let unit = FixedVec2::from_f32(3.0, -4.0).normalize();
+        assert_eq!(unit, FixedVec2::from_f32(0.6, -0.8));
+        assert_eq!(
+            FixedVec2::from_f32(0.0, 1000.0).normalize(),
+            FixedVec2::new(Fixed::ZERO, Fixed::ONE)
+        );
+    }
+}
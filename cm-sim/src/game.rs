@@ -1,82 +1,412 @@
-use std::time::Duration;
+use std::{
+    hash::{Hash, Hasher},
+    time::Duration,
+};
 
 use cm_shared_data::{Input, InputType};
-use nalgebra::{point, Point2, Vector2};
 
-#[derive(Copy, Clone, Debug)]
+use crate::{
+    checksum::StateHasher,
+    entities::{Entity, EntityStore},
+    fixed::{Fixed, FixedVec2},
+    map::Map,
+    spatial::SpatialGrid,
+};
+
+const CIRCLE_RADIUS: Fixed = Fixed::from_int(5);
+// A few circles across, so each circle only lands in a cell or four
+const GRID_CELL_SIZE: Fixed = Fixed::from_int(32);
+
+#[derive(Copy, Clone, Debug, Hash)]
 pub struct Circle {
     pub player_id: i32,
-    pub circle_id: i64, // auto-incrementing
-    pub speed: f32,     // map units per second
-    pub position: Point2<f32>,
-    pub destination: Option<Point2<f32>>,
+    pub circle_id: i64, // never reused
+    pub speed: Fixed,   // map units per second
+    pub radius: Fixed,  // map units
+    pub position: FixedVec2,
+    pub destination: Option<FixedVec2>,
+    // The destination it last arrived at, lets circles sent to the same spot
+    // crowd around it instead of fighting over it
+    pub arrived_at: Option<FixedVec2>,
+}
+
+impl Entity for Circle {
+    fn id(&self) -> i64 {
+        self.circle_id
+    }
+}
+
+impl Circle {
+    fn arrive(&mut self) -> GameEvent {
+        self.arrived_at = self.destination.take();
+        GameEvent::Arrived {
+            circle_id: self.circle_id,
+        }
+    }
+}
+
+/// Something that happened in the game during a step
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GameEvent {
+    // The circle reached its destination and stopped
+    Arrived { circle_id: i64 },
 }
 
 #[derive(Clone, Debug)]
 pub struct Game {
-    step_dt: Duration,
-    pub circles: Vec<Circle>,
+    step_dt: Fixed, // seconds
+    map: Map,
+    circles: EntityStore<Circle>,
+    // The id the next circle will get
+    next_circle_id: i64,
+    // Where the circles are, kept current as they move
+    grid: SpatialGrid,
 }
 
 impl Game {
     pub fn new(step_dt: Duration) -> Game {
+        Game::with_map(step_dt, Map::default())
+    }
+
+    pub fn with_map(step_dt: Duration, map: Map) -> Game {
         Game {
-            step_dt,
-            circles: Vec::new(),
+            step_dt: Fixed::from_duration(step_dt),
+            map,
+            circles: EntityStore::new(),
+            next_circle_id: 0,
+            grid: SpatialGrid::new(GRID_CELL_SIZE),
         }
     }
 
-    pub fn step(&mut self) {
-        self.step_movement();
+    /// A deterministic hash of the full game state, equal on every peer
+    /// that has processed the same inputs. The grid and the circle id index
+    /// are left out, they're rebuilt from the circles.
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = StateHasher::new();
+        self.step_dt.hash(&mut hasher);
+        self.map.hash(&mut hasher);
+        self.circles.as_slice().hash(&mut hasher);
+        self.next_circle_id.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn step(&mut self) -> Vec<GameEvent> {
+        let mut events = self.step_movement();
+        self.grid.rebuild(self.circles.as_slice());
+        events.extend(self.separate_circles());
+        // Last so nothing's left pushed into a wall or off the map
+        self.keep_circles_on_map();
+        // Circles have been shuffled around a bit, get them back where they
+        // actually are for anyone querying between steps
+        self.grid.rebuild(self.circles.as_slice());
+        events
+    }
+
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+
+    /// Every circle, in an order that's the same on every peer
+    pub fn circles(&self) -> &[Circle] {
+        self.circles.as_slice()
+    }
+
+    pub fn circle(&self, circle_id: i64) -> Option<&Circle> {
+        self.circles.get(circle_id)
+    }
+
+    /// Ids of the circles overlapping the circle at `center`, ascending
+    pub fn circles_in_radius(&self, center: FixedVec2, radius: Fixed) -> Vec<i64> {
+        let found = self.grid.query_radius(self.circles(), center, radius);
+        self.circle_ids(found)
+    }
+
+    /// Ids of the circles overlapping the rect from `min` to `max`, ascending
+    pub fn circles_in_rect(&self, min: FixedVec2, max: FixedVec2) -> Vec<i64> {
+        let found = self.grid.query_rect(self.circles(), min, max);
+        self.circle_ids(found)
     }
 
+    fn circle_ids(&self, indexes: Vec<usize>) -> Vec<i64> {
+        let mut ids: Vec<i64> = indexes
+            .into_iter()
+            .map(|i| self.circles()[i].circle_id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    // Input coordinates are floats on the wire, this is the only place they
+    // enter the sim so convert them to fixed point here.
     pub fn handle_input(&mut self, input: Input) {
         match input.input_type {
-            InputType::CreateCircle { x, y } => self.add_circle(point![x, y], input.player_id),
+            InputType::CreateCircle { x, y } => {
+                self.add_circle(FixedVec2::from_f32(x, y), input.player_id)
+            }
             InputType::SetDestination { circle_id, x, y } => {
                 if self.circle_owned_by(circle_id, input.player_id) {
-                    self.set_destination(point![x, y], circle_id)
+                    self.set_destination(FixedVec2::from_f32(x, y), circle_id)
                 }
             }
+            // Controls for the sim running the game, not the game itself
+            InputType::Pause | InputType::Resume | InputType::SetSpeed { .. } => {}
         }
     }
 
-    pub fn add_circle(&mut self, position: Point2<f32>, player_id: i32) {
-        self.circles.push(Circle {
+    pub fn add_circle(&mut self, position: FixedVec2, player_id: i32) {
+        let circle = Circle {
             player_id,
-            // Will panic if unit count is higher than i64, unlikely
-            circle_id: i64::try_from(self.circles.len()).unwrap(),
-            speed: 20.0,
-            position,
+            circle_id: self.next_circle_id,
+            speed: Fixed::from_int(20),
+            radius: CIRCLE_RADIUS,
+            position: self.map.push_out(position, CIRCLE_RADIUS),
             destination: None,
-        })
+            arrived_at: None,
+        };
+        self.next_circle_id += 1;
+        self.grid.insert(self.circles.len(), &circle);
+        self.circles.insert(circle);
+    }
+
+    pub fn remove_circle(&mut self, circle_id: i64) -> Option<Circle> {
+        let (index, circle) = self.circles.remove(circle_id)?;
+        self.grid.remove(index, &circle);
+        // The last circle was moved into the gap
+        let last = self.circles.len();
+        if let Some(moved) = self.circles.as_slice().get(index) {
+            self.grid.reindex(last, index, moved);
+        }
+        Some(circle)
     }
-    pub fn set_destination(&mut self, destination: Point2<f32>, circle_id: i64) {
-        let circle = self.circles.iter_mut().find(|c| c.circle_id == circle_id);
-        if let Some(c) = circle {
-            c.destination = Some(destination)
+
+    pub fn set_destination(&mut self, destination: FixedVec2, circle_id: i64) {
+        if let Some(c) = self.circles.get_mut(circle_id) {
+            // Somewhere it can actually get to, the nearest spot outside any
+            // obstacle and inside the bounds
+            c.destination = Some(self.map.push_out(destination, c.radius));
+            c.arrived_at = None;
         }
     }
-    fn step_movement(&mut self) {
+    fn step_movement(&mut self) -> Vec<GameEvent> {
+        let mut events = vec![];
         for c in self.circles.iter_mut() {
             if let Some(d) = c.destination {
-                let translation_vec: Vector2<f32> = (d - c.position)
-                    .normalize()
-                    .scale(c.speed * self.step_dt.as_secs_f32());
-                let new_pos = c.position + translation_vec;
-                println!(
-                    "pos: {:?}, translation vec: {:?}, new_pos: {:?}",
-                    c.position, translation_vec, new_pos
-                );
-                c.position = new_pos;
+                let travel = c.speed * self.step_dt;
+                let to_destination = d - c.position;
+                // Close enough to make it this step, stop right on it rather
+                // than overshooting and coming back
+                if to_destination.length() <= travel {
+                    c.position = d;
+                    events.push(c.arrive());
+                } else {
+                    c.position += to_destination.normalize().scale(travel);
+                }
             }
         }
+        events
+    }
+
+    /// Pushes overlapping circles apart, each taking half of the overlap.
+    /// Pairs are resolved in the circles' store order so every peer ends up
+    /// with the same positions. Only pairs the grid has close
+    /// together are checked, anything a push moves into is caught next step.
+    fn separate_circles(&mut self) -> Vec<GameEvent> {
+        let mut events = vec![];
+        for j in 1..self.circles.len() {
+            let b = &self.circles()[j];
+            let neighbors = self.grid.query_radius(self.circles(), b.position, b.radius);
+
+            let (before, rest) = self.circles.as_mut_slice().split_at_mut(j);
+            let b = &mut rest[0];
+            for i in neighbors.into_iter().take_while(|&i| i < j) {
+                let a = &mut before[i];
+                let offset = b.position - a.position;
+                let distance = offset.length();
+                let min_distance = a.radius + b.radius;
+                if distance >= min_distance {
+                    continue;
+                }
+
+                events.extend(arrive_in_crowd(a, b));
+                events.extend(arrive_in_crowd(b, a));
+
+                // Right on top of each other there's no direction to push
+                // in, so split them along x
+                let normal = if distance == Fixed::ZERO {
+                    FixedVec2::new(Fixed::ONE, Fixed::ZERO)
+                } else {
+                    offset.normalize()
+                };
+                let push = normal.scale((min_distance - distance) / Fixed::from_int(2));
+                a.position -= push;
+                b.position += push;
+            }
+        }
+        events
+    }
+
+    fn keep_circles_on_map(&mut self) {
+        for c in self.circles.iter_mut() {
+            c.position = self.map.push_out(c.position, c.radius);
+        }
     }
 
     pub fn circle_owned_by(&self, circle_id: i64, player_id: i32) -> bool {
-        match self.circles.iter().find(|c| c.circle_id == circle_id) {
+        match self.circles.get(circle_id) {
             Some(c) => c.player_id == player_id,
             None => false,
         }
     }
 }
+
+/// A moving circle that bumps into one that's already arrived where it's
+/// headed stops there too, the spot's taken
+fn arrive_in_crowd(c: &mut Circle, other: &Circle) -> Option<GameEvent> {
+    if c.destination.is_some() && other.destination.is_none() && c.destination == other.arrived_at {
+        Some(c.arrive())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Circles move 20 units a step
+    fn game() -> Game {
+        Game::with_map(
+            Duration::from_secs(1),
+            Map::parse("bounds 0 0 1000 1000").unwrap(),
+        )
+    }
+
+    fn point(x: i32, y: i32) -> FixedVec2 {
+        FixedVec2::new(Fixed::from_int(x), Fixed::from_int(y))
+    }
+
+    #[test]
+    fn arrives_right_on_its_destination() {
+        let mut game = game();
+        game.add_circle(point(100, 100), 0);
+        game.set_destination(point(130, 100), 0);
+
+        assert_eq!(game.step(), vec![]);
+        assert_eq!(game.circle(0).unwrap().position, point(120, 100));
+
+        // Only 10 to go, it stops there instead of overshooting
+        assert_eq!(game.step(), vec![GameEvent::Arrived { circle_id: 0 }]);
+        let circle = game.circle(0).unwrap();
+        assert_eq!(circle.position, point(130, 100));
+        assert_eq!(circle.destination, None);
+        assert_eq!(circle.arrived_at, Some(point(130, 100)));
+
+        // And stays put
+        assert_eq!(game.step(), vec![]);
+        assert_eq!(game.circle(0).unwrap().position, point(130, 100));
+    }
+
+    #[test]
+    fn a_new_destination_forgets_the_last_arrival() {
+        let mut game = game();
+        game.add_circle(point(100, 100), 0);
+        game.set_destination(point(100, 100), 0);
+        assert_eq!(game.step(), vec![GameEvent::Arrived { circle_id: 0 }]);
+
+        game.set_destination(point(200, 100), 0);
+        assert_eq!(game.circle(0).unwrap().arrived_at, None);
+    }
+
+    #[test]
+    fn overlapping_circles_split_the_overlap() {
+        let mut game = game();
+        game.add_circle(point(100, 100), 0);
+        game.add_circle(point(104, 100), 0);
+        game.step();
+        assert_eq!(game.circle(0).unwrap().position, point(97, 100));
+        assert_eq!(game.circle(1).unwrap().position, point(107, 100));
+    }
+
+    #[test]
+    fn circles_on_top_of_each_other_split_along_x() {
+        let mut game = game();
+        game.add_circle(point(100, 100), 0);
+        game.add_circle(point(100, 100), 1);
+        game.step();
+        assert_eq!(game.circle(0).unwrap().position, point(95, 100));
+        assert_eq!(game.circle(1).unwrap().position, point(105, 100));
+    }
+
+    #[test]
+    fn circles_sent_to_a_taken_spot_stop_at_the_crowd() {
+        // Slow enough that circles overlap before they'd reach the spot
+        let mut game = Game::with_map(
+            Duration::from_millis(100),
+            Map::parse("bounds 0 0 1000 1000").unwrap(),
+        );
+        game.add_circle(point(200, 100), 0);
+        game.set_destination(point(200, 100), 0);
+        assert_eq!(game.step(), vec![GameEvent::Arrived { circle_id: 0 }]);
+
+        game.add_circle(point(150, 100), 0);
+        game.set_destination(point(200, 100), 1);
+        let mut arrived = false;
+        for _ in 0..100 {
+            if game.step().contains(&GameEvent::Arrived { circle_id: 1 }) {
+                arrived = true;
+                break;
+            }
+        }
+        assert!(arrived);
+        let circle = game.circle(1).unwrap();
+        assert_eq!(circle.destination, None);
+        assert!(circle.position.x < Fixed::from_int(195));
+    }
+
+    #[test]
+    fn moving_circles_dont_stop_for_strangers() {
+        let mut game = Game::with_map(
+            Duration::from_millis(100),
+            Map::parse("bounds 0 0 1000 1000").unwrap(),
+        );
+        // Arrived somewhere else
+        game.add_circle(point(200, 100), 0);
+        game.set_destination(point(200, 100), 0);
+        game.step();
+
+        game.add_circle(point(150, 100), 0);
+        game.set_destination(point(250, 100), 1);
+        // Long enough to run into it
+        for _ in 0..30 {
+            assert!(!game.step().contains(&GameEvent::Arrived { circle_id: 1 }));
+        }
+        assert_eq!(game.circle(1).unwrap().destination, Some(point(250, 100)));
+    }
+
+    #[test]
+    fn removed_circles_leave_the_rest_findable() {
+        let mut game = game();
+        game.add_circle(point(100, 100), 0);
+        game.add_circle(point(300, 100), 0);
+        game.add_circle(point(500, 100), 0);
+
+        let removed = game.remove_circle(0).unwrap();
+        assert_eq!(removed.circle_id, 0);
+        assert!(game.remove_circle(0).is_none());
+        assert_eq!(
+            game.circles()
+                .iter()
+                .map(|c| c.circle_id)
+                .collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+
+        // Circle 2 was moved into circle 0's slot, the grid has to follow it
+        assert_eq!(game.circles_in_radius(point(100, 100), Fixed::ONE), vec![]);
+        assert_eq!(game.circles_in_radius(point(500, 100), Fixed::ONE), vec![2]);
+        assert_eq!(
+            game.circles_in_rect(point(0, 0), point(1000, 1000)),
+            vec![1, 2]
+        );
+    }
+}
@@ -1,2 +1,9 @@
 pub mod actor;
+pub mod checksum;
+pub mod entities;
+pub mod fixed;
 pub mod game;
+pub mod map;
+pub mod replay;
+pub mod simulation;
+pub mod spatial;
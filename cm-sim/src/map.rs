@@ -0,0 +1,296 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+use crate::fixed::{Fixed, FixedVec2};
+
+/// The map played when nobody's picked one, see `maps/default.map`
+pub const DEFAULT_MAP: &str = include_str!("../maps/default.map");
+
+/// Somewhere circles can't go
+#[derive(Clone, Debug, Hash)]
+pub enum Obstacle {
+    Circle { center: FixedVec2, radius: Fixed },
+    // Wound so its signed area is positive, however it was written in the
+    // map file
+    Polygon { points: Vec<FixedVec2> },
+}
+
+/// The static layout of the world the game is played in, the playable area
+/// and the obstacles in it.
+#[derive(Clone, Debug, Hash)]
+pub struct Map {
+    // The text it was parsed from, everything needed to build it again
+    source: String,
+    min: FixedVec2,
+    max: FixedVec2,
+    obstacles: Vec<Obstacle>,
+}
+
+impl Default for Map {
+    fn default() -> Self {
+        Map::parse(DEFAULT_MAP).expect("The built in map is broken")
+    }
+}
+
+impl Map {
+    pub fn load(path: &Path) -> Result<Map> {
+        let text = fs::read_to_string(path)?;
+        Map::parse(&text).with_context(|| format!("Failed to load map {}", path.display()))
+    }
+
+    /// Reads a map from its text format, see `maps/default.map`
+    pub fn parse(text: &str) -> Result<Map> {
+        let mut bounds = None;
+        let mut obstacles = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let kind = words.next().unwrap_or_default();
+            let numbers = words
+                .map(|w| w.parse::<f32>().map(Fixed::from_f32))
+                .collect::<Result<Vec<Fixed>, _>>()
+                .with_context(|| format!("Line {}: bad number", i + 1))?;
+
+            match (kind, numbers.as_slice()) {
+                ("bounds", &[min_x, min_y, max_x, max_y]) => {
+                    if min_x >= max_x || min_y >= max_y {
+                        bail!("Line {}: bounds are empty", i + 1);
+                    }
+                    bounds = Some((FixedVec2::new(min_x, min_y), FixedVec2::new(max_x, max_y)));
+                }
+                ("circle", &[x, y, radius]) => obstacles.push(Obstacle::Circle {
+                    center: FixedVec2::new(x, y),
+                    radius,
+                }),
+                ("polygon", coordinates)
+                    if coordinates.len() >= 6 && coordinates.len() % 2 == 0 =>
+                {
+                    let mut points: Vec<FixedVec2> = coordinates
+                        .chunks(2)
+                        .map(|xy| FixedVec2::new(xy[0], xy[1]))
+                        .collect();
+                    if signed_area(&points) < Fixed::ZERO {
+                        points.reverse();
+                    }
+                    obstacles.push(Obstacle::Polygon { points });
+                }
+                _ => bail!("Line {}: don't know what '{}' is", i + 1, line),
+            }
+        }
+
+        match bounds {
+            Some((min, max)) => Ok(Map {
+                source: text.to_string(),
+                min,
+                max,
+                obstacles,
+            }),
+            None => bail!("The map has no bounds"),
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The corners of the playable area
+    pub fn bounds(&self) -> (FixedVec2, FixedVec2) {
+        (self.min, self.max)
+    }
+
+    pub fn obstacles(&self) -> &[Obstacle] {
+        &self.obstacles
+    }
+
+    /// The closest place to `position` a circle of `radius` fits inside the
+    /// bounds. Obstacles aren't taken into account.
+    pub fn clamp(&self, position: FixedVec2, radius: Fixed) -> FixedVec2 {
+        FixedVec2::new(
+            clamp_axis(position.x, self.min.x + radius, self.max.x - radius),
+            clamp_axis(position.y, self.min.y + radius, self.max.y - radius),
+        )
+    }
+
+    /// Moves a circle of `radius` at `position` out of any obstacles it's
+    /// overlapping and back inside the bounds. Obstacles are handled in the
+    /// order they're in the map file.
+    pub fn push_out(&self, position: FixedVec2, radius: Fixed) -> FixedVec2 {
+        let mut position = position;
+        for obstacle in self.obstacles.iter() {
+            position = match obstacle {
+                Obstacle::Circle { center, radius: r } => {
+                    push_out_of_circle(position, radius, *center, *r)
+                }
+                Obstacle::Polygon { points } => push_out_of_polygon(position, radius, points),
+            };
+        }
+        self.clamp(position, radius)
+    }
+}
+
+// Circles too big for the bounds end up in the middle
+fn clamp_axis(value: Fixed, min: Fixed, max: Fixed) -> Fixed {
+    if min > max {
+        return (min + max) / Fixed::from_int(2);
+    }
+    value.clamp(min, max)
+}
+
+fn push_out_of_circle(
+    position: FixedVec2,
+    radius: Fixed,
+    center: FixedVec2,
+    obstacle_radius: Fixed,
+) -> FixedVec2 {
+    let offset = position - center;
+    let distance = offset.length();
+    let min_distance = radius + obstacle_radius;
+    if distance >= min_distance {
+        return position;
+    }
+    // Dead center there's no way out that's closer than another, pick one
+    let normal = if distance == Fixed::ZERO {
+        FixedVec2::new(Fixed::ONE, Fixed::ZERO)
+    } else {
+        offset.normalize()
+    };
+    center + normal.scale(min_distance)
+}
+
+fn push_out_of_polygon(position: FixedVec2, radius: Fixed, points: &[FixedVec2]) -> FixedVec2 {
+    // The closest point on the polygon's outline, and the outward normal of
+    // the edge it's on
+    let mut closest = (Fixed::ZERO, FixedVec2::ZERO, FixedVec2::ZERO);
+    for (i, &a) in points.iter().enumerate() {
+        let b = points[(i + 1) % points.len()];
+        let point = closest_on_segment(position, a, b);
+        let distance = (position - point).length();
+        if i == 0 || distance < closest.0 {
+            let edge = b - a;
+            closest = (distance, point, FixedVec2::new(edge.y, -edge.x).normalize());
+        }
+    }
+    let (distance, point, edge_normal) = closest;
+
+    if contains(points, position) || distance == Fixed::ZERO {
+        point + edge_normal.scale(radius)
+    } else if distance < radius {
+        // Outside but overlapping, going straight away from the closest point
+        // rounds corners off nicely
+        point + (position - point).normalize().scale(radius)
+    } else {
+        position
+    }
+}
+
+fn closest_on_segment(position: FixedVec2, a: FixedVec2, b: FixedVec2) -> FixedVec2 {
+    let edge = b - a;
+    let length_squared = edge.dot(edge);
+    if length_squared == Fixed::ZERO {
+        return a;
+    }
+    let t = ((position - a).dot(edge) / length_squared).clamp(Fixed::ZERO, Fixed::ONE);
+    a + edge.scale(t)
+}
+
+// Even-odd rule, counting the edges a ray going right from `position` crosses
+fn contains(points: &[FixedVec2], position: FixedVec2) -> bool {
+    let mut inside = false;
+    for (i, &a) in points.iter().enumerate() {
+        let b = points[(i + 1) % points.len()];
+        if (a.y > position.y) != (b.y > position.y) {
+            let crossing_x = a.x + (position.y - a.y) * (b.x - a.x) / (b.y - a.y);
+            if position.x < crossing_x {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+// Positive when the points go counter-clockwise with y up
+fn signed_area(points: &[FixedVec2]) -> Fixed {
+    let mut twice_area = Fixed::ZERO;
+    for (i, &a) in points.iter().enumerate() {
+        let b = points[(i + 1) % points.len()];
+        twice_area += a.x * b.y - b.x * a.y;
+    }
+    twice_area / Fixed::from_int(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: i32, y: i32) -> FixedVec2 {
+        FixedVec2::new(Fixed::from_int(x), Fixed::from_int(y))
+    }
+
+    #[test]
+    fn parses_the_default_map() {
+        let map = Map::default();
+        assert_eq!(map.bounds(), (point(0, 0), point(1152, 648)));
+        assert_eq!(map.obstacles().len(), 3);
+        assert_eq!(map.source(), DEFAULT_MAP);
+    }
+
+    #[test]
+    fn parses_every_kind_of_line() {
+        let map = Map::parse(
+            "# A comment\n\
+             \n\
+             bounds -10 -10 10 10\n\
+             circle 1 2 3\n\
+             polygon 0 0 0 1 1 1 1 0\n",
+        )
+        .unwrap();
+        assert_eq!(map.bounds(), (point(-10, -10), point(10, 10)));
+        match &map.obstacles()[0] {
+            Obstacle::Circle { center, radius } => {
+                assert_eq!(*center, point(1, 2));
+                assert_eq!(*radius, Fixed::from_int(3));
+            }
+            other => panic!("Expected a circle, got {:?}", other),
+        }
+        match &map.obstacles()[1] {
+            // Written clockwise, so it's been turned around
+            Obstacle::Polygon { points } => {
+                assert_eq!(
+                    points,
+                    &[point(1, 0), point(1, 1), point(0, 1), point(0, 0)]
+                );
+            }
+            other => panic!("Expected a polygon, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_maps() {
+        assert!(Map::parse("").is_err());
+        assert!(Map::parse("circle 1 2 3").is_err());
+        assert!(Map::parse("bounds 0 0 0 10").is_err());
+        assert!(Map::parse("bounds 0 0 10 10\ncircle 1 2").is_err());
+        assert!(Map::parse("bounds 0 0 10 10\ncircle 1 two 3").is_err());
+        assert!(Map::parse("bounds 0 0 10 10\npolygon 0 0 1 1").is_err());
+        assert!(Map::parse("bounds 0 0 10 10\ntree 1 1").is_err());
+    }
+
+    #[test]
+    fn push_out() {
+        let map =
+            Map::parse("bounds 0 0 100 100\ncircle 50 50 10\npolygon 0 0 20 0 20 20 0 20").unwrap();
+        let radius = Fixed::from_int(5);
+        // Clear of everything
+        assert_eq!(map.push_out(point(80, 80), radius), point(80, 80));
+        // Into the circle
+        assert_eq!(map.push_out(point(52, 50), radius), point(65, 50));
+        // Into the polygon, out of its closest edge
+        assert_eq!(map.push_out(point(18, 10), radius), point(25, 10));
+        // Off the map
+        assert_eq!(map.push_out(point(200, 50), radius), point(95, 50));
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use cm_shared_data::Input;
+use serde::{Deserialize, Serialize};
+
+/// Replay files start with these bytes followed by the format version as a
+/// little endian u32, everything after that is specific to the version.
+const REPLAY_MAGIC: [u8; 4] = *b"CMRP";
+pub const REPLAY_VERSION: u32 = 2;
+
+const REPLAY_DIR: &str = "replays";
+
+/// Everything needed to recreate the sim a replay was recorded from
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayHeader {
+    pub tick_duration: Duration,
+    pub recorded_at: SystemTime,
+    // When tick 0 was due
+    pub start_at: SystemTime,
+    // The lockstep players whose input every tick waited on
+    pub players: Vec<i32>,
+    // The map file it was played on, see `Map::parse`
+    pub map: String,
+}
+
+/// A fully loaded replay file
+pub struct Replay {
+    pub header: ReplayHeader,
+    // In the order they were fed to the sim
+    pub inputs: Vec<Input>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Replay> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != REPLAY_MAGIC {
+            bail!("{} is not a replay file", path.display());
+        }
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != REPLAY_VERSION {
+            bail!(
+                "Unsupported replay version {}, expected {}",
+                version,
+                REPLAY_VERSION
+            );
+        }
+
+        let header: ReplayHeader = bincode::deserialize_from(&mut reader)?;
+        let mut inputs = Vec::new();
+        loop {
+            match bincode::deserialize_from(&mut reader) {
+                Ok(input) => inputs.push(input),
+                Err(e) => match *e {
+                    // Inputs run until the end of the file
+                    bincode::ErrorKind::Io(ref io) if io.kind() == ErrorKind::UnexpectedEof => {
+                        break
+                    }
+                    _ => return Err(e.into()),
+                },
+            }
+        }
+
+        Ok(Replay { header, inputs })
+    }
+}
+
+/// Streams a replay to disk as inputs arrive
+pub struct ReplayWriter {
+    writer: BufWriter<File>,
+}
+
+impl ReplayWriter {
+    pub fn create(path: &Path, header: &ReplayHeader) -> Result<ReplayWriter> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Never clobber an earlier replay
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&REPLAY_MAGIC)?;
+        writer.write_all(&REPLAY_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, header)?;
+        writer.flush()?;
+        Ok(ReplayWriter { writer })
+    }
+
+    pub fn record_input(&mut self, input: &Input) -> Result<()> {
+        bincode::serialize_into(&mut self.writer, input)?;
+        // Flush every input so a crash still leaves a usable replay
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A timestamped path in the replays directory, e.g.
+/// `replays/server-7-1700000000123456789.cmreplay`. Anything in `prefix` that
+/// isn't a letter, digit, `-` or `_` is replaced so it can't leave the
+/// directory.
+pub fn new_replay_path(prefix: &str) -> PathBuf {
+    let prefix: String = prefix
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    Path::new(REPLAY_DIR).join(format!("{}-{}.cmreplay", prefix, nanos))
+}
@@ -0,0 +1,367 @@
+use std::collections::{BTreeSet, HashMap};
+
+use cm_shared_data::{valid_speed, Input, InputBundle, InputType};
+use queues::{IsQueue, Queue};
+
+use crate::game::{Game, GameEvent};
+
+/// The state published after every tick
+#[derive(Clone, Debug)]
+pub struct SimSnapshot {
+    pub tick: i32,
+    pub checksum: u64,
+    pub game: Game,
+    // Players whose input the sim is stalled waiting on, empty while running
+    pub waiting_on: Vec<i32>,
+    // How many ticks behind the wall clock the sim is, negative if ahead
+    pub tick_lag: i32,
+    pub paused: bool,
+    pub speed: f32,
+}
+
+impl SimSnapshot {
+    pub fn new(tick: i32, game: Game) -> SimSnapshot {
+        SimSnapshot {
+            tick,
+            checksum: game.checksum(),
+            game,
+            waiting_on: vec![],
+            tick_lag: 0,
+            paused: false,
+            speed: 1.0,
+        }
+    }
+}
+
+/// A tick couldn't be processed because not every player's input for it
+/// has arrived yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stalled {
+    pub tick: i32,
+    pub missing_players: Vec<i32>,
+}
+
+/// What to do with an input for a tick that's already been processed
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum LateInputPolicy {
+    // Drop it, for sims that have to agree with everyone else's
+    #[default]
+    Reject,
+    // Process it on the next tick instead, for sims that decide what
+    // happens on their own
+    Reschedule,
+}
+
+/// An input that showed up after its tick had already been processed
+#[derive(Debug, Copy, Clone)]
+pub struct LateInput {
+    pub input: Input,
+    // The tick the sim was on when it arrived
+    pub current_tick: i32,
+    // What was done with it
+    pub policy: LateInputPolicy,
+}
+
+/// The game plus everything needed to advance it tick by tick, with no clock
+/// attached. Anything driving the sim (the sim actor, replays, tests, bots)
+/// goes through this so they all share the exact same stepping logic.
+pub struct Simulation {
+    game: Game,
+    // Hashmap as a sparse array indexed by tick
+    input_buffer: HashMap<i32, Queue<Input>>,
+    // The next tick to be processed
+    current_tick: i32,
+    // Players that must confirm their input for a tick before it can be
+    // processed, with no players every tick is processed right away
+    players: BTreeSet<i32>,
+    // Sparse by tick, the players that have confirmed their input for it
+    confirmed: HashMap<i32, BTreeSet<i32>>,
+    late_input_policy: LateInputPolicy,
+    // While paused ticks are still processed but the game isn't stepped
+    paused: bool,
+    // How fast ticks should be run against the wall clock, it's up to
+    // whoever's driving the sim to honor it
+    speed: f32,
+}
+
+impl Simulation {
+    pub fn new(game: Game) -> Simulation {
+        Simulation::with_players(game, [])
+    }
+
+    /// A lockstep sim that holds every tick until each of `players` has sent
+    /// an input bundle for it.
+    pub fn with_players<P: IntoIterator<Item = i32>>(game: Game, players: P) -> Simulation {
+        Simulation {
+            game,
+            input_buffer: HashMap::new(),
+            current_tick: 0,
+            players: players.into_iter().collect(),
+            confirmed: HashMap::new(),
+            late_input_policy: LateInputPolicy::default(),
+            paused: false,
+            speed: 1.0,
+        }
+    }
+
+    pub fn set_late_input_policy(&mut self, policy: LateInputPolicy) {
+        self.late_input_policy = policy;
+    }
+
+    pub fn current_tick(&self) -> i32 {
+        self.current_tick
+    }
+
+    /// The lockstep players, in id order
+    pub fn players(&self) -> Vec<i32> {
+        self.players.iter().copied().collect()
+    }
+
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn snapshot(&self) -> SimSnapshot {
+        SimSnapshot {
+            paused: self.paused,
+            speed: self.speed,
+            ..SimSnapshot::new(self.current_tick, self.game.clone())
+        }
+    }
+
+    /// Queues an input to be processed on its `for_tick`. If that tick has
+    /// already been processed the late input policy decides what happens to
+    /// it and it's handed back.
+    pub fn buffer_input(&mut self, mut input: Input) -> Option<LateInput> {
+        let mut late = None;
+        if input.for_tick < self.current_tick {
+            late = Some(LateInput {
+                input,
+                current_tick: self.current_tick,
+                policy: self.late_input_policy,
+            });
+            match self.late_input_policy {
+                LateInputPolicy::Reject => return late,
+                LateInputPolicy::Reschedule => input.for_tick = self.current_tick,
+            }
+        }
+
+        if let Some(tick_buffer) = self.input_buffer.get_mut(&input.for_tick) {
+            tick_buffer.add(input).unwrap();
+        } else {
+            let mut queue = Queue::new();
+            queue.add(input).unwrap();
+            self.input_buffer.insert(input.for_tick, queue);
+        }
+        late
+    }
+
+    /// Buffers a player's inputs for a tick and confirms that they're all the
+    /// player will send for it, handing back any that were late.
+    pub fn buffer_bundle(&mut self, bundle: InputBundle) -> Vec<LateInput> {
+        let late = bundle
+            .inputs
+            .into_iter()
+            .filter_map(|input| self.buffer_input(input))
+            .collect();
+        if bundle.for_tick >= self.current_tick {
+            self.confirmed
+                .entry(bundle.for_tick)
+                .or_default()
+                .insert(bundle.player_id);
+        }
+        late
+    }
+
+    /// Players that haven't confirmed their input for the next tick yet
+    pub fn missing_players(&self) -> Vec<i32> {
+        match self.confirmed.get(&self.current_tick) {
+            Some(confirmed) => self.players.difference(confirmed).copied().collect(),
+            None => self.players.iter().copied().collect(),
+        }
+    }
+
+    /// Buffers `inputs` and then processes a single tick, handing back what
+    /// happened in the game on it
+    pub fn advance<I: IntoIterator<Item = Input>>(
+        &mut self,
+        inputs: I,
+    ) -> Result<Vec<GameEvent>, Stalled> {
+        for input in inputs {
+            self.buffer_input(input);
+        }
+        self.step()
+    }
+
+    /// Processes ticks with whatever input is buffered until `tick` is the
+    /// next one to be processed, stopping early if a tick stalls. Game events
+    /// along the way are dropped.
+    pub fn advance_to(&mut self, tick: i32) -> Result<(), Stalled> {
+        while self.current_tick < tick {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<Vec<GameEvent>, Stalled> {
+        let missing_players = self.missing_players();
+        if !missing_players.is_empty() {
+            return Err(Stalled {
+                tick: self.current_tick,
+                missing_players,
+            });
+        }
+        self.confirmed.remove(&self.current_tick);
+
+        // Process all buffered input for this tick
+        if let Some(ref mut tick_buffer) = self.input_buffer.remove(&self.current_tick) {
+            while let Ok(input) = tick_buffer.remove() {
+                match input.input_type {
+                    InputType::Pause => self.paused = true,
+                    InputType::Resume => self.paused = false,
+                    // Speeds out of range can't be run at so they're ignored
+                    InputType::SetSpeed { speed } if valid_speed(speed) => self.speed = speed,
+                    _ => self.game.handle_input(input),
+                }
+            }
+        }
+
+        let events = if self.paused {
+            vec![]
+        } else {
+            self.game.step()
+        };
+        self.current_tick += 1;
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn create_circle(for_tick: i32, player_id: i32) -> Input {
+        Input {
+            for_tick,
+            player_id,
+            input_type: InputType::CreateCircle { x: 100.0, y: 100.0 },
+        }
+    }
+
+    fn bundle(for_tick: i32, player_id: i32, inputs: Vec<Input>) -> InputBundle {
+        InputBundle {
+            for_tick,
+            player_id,
+            inputs,
+        }
+    }
+
+    fn lockstep_sim() -> Simulation {
+        Simulation::with_players(Game::new(Duration::from_millis(50)), [0, 1])
+    }
+
+    #[test]
+    fn stalls_until_every_player_confirms() {
+        let mut sim = lockstep_sim();
+        assert_eq!(sim.missing_players(), vec![0, 1]);
+
+        sim.buffer_bundle(bundle(0, 1, vec![create_circle(0, 1)]));
+        assert_eq!(sim.missing_players(), vec![0]);
+        assert_eq!(
+            sim.advance([]),
+            Err(Stalled {
+                tick: 0,
+                missing_players: vec![0],
+            })
+        );
+        assert_eq!(sim.current_tick(), 0);
+        assert_eq!(sim.game().circles().len(), 0);
+
+        sim.buffer_bundle(bundle(0, 0, vec![]));
+        assert!(sim.missing_players().is_empty());
+        assert!(sim.advance([]).is_ok());
+        assert_eq!(sim.current_tick(), 1);
+        assert_eq!(sim.game().circles().len(), 1);
+
+        // Confirmations only count for the tick they were sent for
+        assert_eq!(sim.missing_players(), vec![0, 1]);
+    }
+
+    #[test]
+    fn advance_to_stops_at_the_first_stall() {
+        let mut sim = lockstep_sim();
+        for tick in 0..3 {
+            sim.buffer_bundle(bundle(tick, 0, vec![]));
+            sim.buffer_bundle(bundle(tick, 1, vec![]));
+        }
+        sim.buffer_bundle(bundle(3, 0, vec![]));
+        assert_eq!(
+            sim.advance_to(10),
+            Err(Stalled {
+                tick: 3,
+                missing_players: vec![1],
+            })
+        );
+        assert_eq!(sim.current_tick(), 3);
+    }
+
+    #[test]
+    fn late_input_is_rejected_by_default() {
+        let mut sim = Simulation::new(Game::new(Duration::from_millis(50)));
+        sim.advance_to(5).unwrap();
+
+        let late = sim.buffer_input(create_circle(2, 0)).unwrap();
+        assert_eq!(late.current_tick, 5);
+        assert_eq!(late.policy, LateInputPolicy::Reject);
+        sim.advance_to(10).unwrap();
+        assert_eq!(sim.game().circles().len(), 0);
+    }
+
+    #[test]
+    fn late_input_can_be_rescheduled() {
+        let mut sim = Simulation::new(Game::new(Duration::from_millis(50)));
+        sim.set_late_input_policy(LateInputPolicy::Reschedule);
+        sim.advance_to(5).unwrap();
+
+        let late = sim.buffer_input(create_circle(2, 0)).unwrap();
+        assert_eq!(late.input.for_tick, 2);
+        assert_eq!(late.policy, LateInputPolicy::Reschedule);
+        sim.advance([]).unwrap();
+        assert_eq!(sim.game().circles().len(), 1);
+    }
+
+    #[test]
+    fn late_bundles_dont_confirm_processed_ticks() {
+        let mut sim = lockstep_sim();
+        sim.buffer_bundle(bundle(0, 0, vec![]));
+        sim.buffer_bundle(bundle(0, 1, vec![]));
+        sim.advance([]).unwrap();
+
+        let late = sim.buffer_bundle(bundle(0, 0, vec![create_circle(0, 0)]));
+        assert_eq!(late.len(), 1);
+        assert_eq!(sim.missing_players(), vec![0, 1]);
+    }
+
+    #[test]
+    fn speeds_out_of_range_are_ignored() {
+        let mut sim = Simulation::new(Game::new(Duration::from_millis(50)));
+        let set_speed = |speed| Input {
+            for_tick: 0,
+            player_id: 0,
+            input_type: InputType::SetSpeed { speed },
+        };
+        sim.advance([set_speed(2.0), set_speed(f32::NAN), set_speed(1000.0)])
+            .unwrap();
+        assert_eq!(sim.speed(), 2.0);
+    }
+}
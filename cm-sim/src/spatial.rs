@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use crate::{
+    fixed::{Fixed, FixedVec2},
+    game::Circle,
+};
+
+/// A uniform grid bucketing circles by the cells they overlap, so finding the
+/// circles near somewhere doesn't mean checking every circle in the game.
+///
+/// It only holds indexes into the game's circles and is rebuilt from them,
+/// so it's never part of the game state itself. Queries hand back indexes in
+/// ascending order whatever order the cells are stored in, keeping anything
+/// built on them deterministic.
+#[derive(Clone, Debug)]
+pub struct SpatialGrid {
+    cell_size: Fixed,
+    cells: HashMap<(i64, i64), Vec<usize>>,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: Fixed) -> SpatialGrid {
+        SpatialGrid {
+            cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    pub fn rebuild(&mut self, circles: &[Circle]) {
+        self.cells.clear();
+        for (index, circle) in circles.iter().enumerate() {
+            self.insert(index, circle);
+        }
+    }
+
+    /// Adds the circle at `index` to every cell it overlaps
+    pub fn insert(&mut self, index: usize, circle: &Circle) {
+        for cell in self.circle_cells(circle) {
+            self.cells.entry(cell).or_default().push(index);
+        }
+    }
+
+    /// Takes the circle at `index` back out, it has to be where it was when
+    /// it went in
+    pub fn remove(&mut self, index: usize, circle: &Circle) {
+        for cell in self.circle_cells(circle) {
+            if let Some(indexes) = self.cells.get_mut(&cell) {
+                indexes.retain(|&i| i != index);
+                if indexes.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    /// Follows a circle that's been moved from index `from` to `to`
+    pub fn reindex(&mut self, from: usize, to: usize, circle: &Circle) {
+        for cell in self.circle_cells(circle) {
+            if let Some(indexes) = self.cells.get_mut(&cell) {
+                for i in indexes.iter_mut().filter(|i| **i == from) {
+                    *i = to;
+                }
+            }
+        }
+    }
+
+    /// Indexes of the circles overlapping the rect from `min` to `max`
+    pub fn query_rect(&self, circles: &[Circle], min: FixedVec2, max: FixedVec2) -> Vec<usize> {
+        let mut found = self.candidates(min, max);
+        found.retain(|&i| {
+            let c = &circles[i];
+            // The closest point in the rect to the circle's center
+            let closest = FixedVec2::new(
+                c.position.x.clamp(min.x, max.x),
+                c.position.y.clamp(min.y, max.y),
+            );
+            (c.position - closest).length() <= c.radius
+        });
+        found
+    }
+
+    /// Indexes of the circles overlapping the circle at `center`
+    pub fn query_radius(&self, circles: &[Circle], center: FixedVec2, radius: Fixed) -> Vec<usize> {
+        let extent = FixedVec2::new(radius, radius);
+        let mut found = self.candidates(center - extent, center + extent);
+        found.retain(|&i| {
+            let c = &circles[i];
+            (c.position - center).length() <= radius + c.radius
+        });
+        found
+    }
+
+    /// Every circle in the cells touching the rect, sorted with no repeats
+    fn candidates(&self, min: FixedVec2, max: FixedVec2) -> Vec<usize> {
+        let (min, max) = self.cell_range(min, max);
+        let mut found = vec![];
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                if let Some(cell) = self.cells.get(&(x, y)) {
+                    found.extend_from_slice(cell);
+                }
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+
+    fn circle_cells(&self, circle: &Circle) -> Vec<(i64, i64)> {
+        let extent = FixedVec2::new(circle.radius, circle.radius);
+        let (min, max) = self.cell_range(circle.position - extent, circle.position + extent);
+        (min.0..=max.0)
+            .flat_map(|x| (min.1..=max.1).map(move |y| (x, y)))
+            .collect()
+    }
+
+    fn cell_range(&self, min: FixedVec2, max: FixedVec2) -> ((i64, i64), (i64, i64)) {
+        (
+            (self.cell(min.x), self.cell(min.y)),
+            (self.cell(max.x), self.cell(max.y)),
+        )
+    }
+
+    fn cell(&self, coordinate: Fixed) -> i64 {
+        coordinate.raw().div_euclid(self.cell_size.raw())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn circle(circle_id: i64, x: i32, y: i32, radius: i32) -> Circle {
+        Circle {
+            player_id: 0,
+            circle_id,
+            speed: Fixed::ZERO,
+            radius: Fixed::from_int(radius),
+            position: FixedVec2::new(Fixed::from_int(x), Fixed::from_int(y)),
+            destination: None,
+            arrived_at: None,
+        }
+    }
+
+    fn point(x: i32, y: i32) -> FixedVec2 {
+        FixedVec2::new(Fixed::from_int(x), Fixed::from_int(y))
+    }
+
+    fn grid(circles: &[Circle]) -> SpatialGrid {
+        let mut grid = SpatialGrid::new(Fixed::from_int(10));
+        grid.rebuild(circles);
+        grid
+    }
+
+    #[test]
+    fn queries_come_back_ascending_without_repeats() {
+        // Spread over several cells, with some spanning more than one
+        let circles = [
+            circle(0, 95, 95, 8),
+            circle(1, 5, 5, 3),
+            circle(2, 55, 15, 12),
+            circle(3, 15, 55, 2),
+            circle(4, 50, 50, 30),
+        ];
+        let grid = grid(&circles);
+        assert_eq!(
+            grid.query_rect(&circles, point(0, 0), point(100, 100)),
+            vec![0, 1, 2, 3, 4]
+        );
+        assert_eq!(
+            grid.query_radius(&circles, point(50, 50), Fixed::from_int(100)),
+            vec![0, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn only_overlapping_circles_are_found() {
+        let circles = [circle(0, 0, 0, 5), circle(1, 9, 0, 5), circle(2, 8, 8, 2)];
+        let grid = grid(&circles);
+        // Touching counts
+        assert_eq!(
+            grid.query_radius(&circles, point(0, 0), Fixed::from_int(4)),
+            vec![0, 1]
+        );
+        // Circle 2's bounding box overlaps the rect but it's too far from the
+        // corner to overlap it
+        assert_eq!(
+            grid.query_rect(&circles, point(-2, -2), point(6, 6)),
+            vec![0, 1]
+        );
+        assert_eq!(
+            grid.query_rect(&circles, point(-2, -2), point(7, 7)),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn negative_coordinates() {
+        let circles = [circle(0, -15, -15, 2), circle(1, 15, 15, 2)];
+        let grid = grid(&circles);
+        assert_eq!(
+            grid.query_radius(&circles, point(-14, -14), Fixed::ONE),
+            vec![0]
+        );
+        assert_eq!(
+            grid.query_rect(&circles, point(-20, -20), point(-1, -1)),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn removed_circles_are_gone() {
+        let circles = [circle(0, 5, 5, 3), circle(1, 6, 6, 3)];
+        let mut grid = grid(&circles);
+        grid.remove(0, &circles[0]);
+        assert_eq!(
+            grid.query_rect(&circles, point(0, 0), point(20, 20)),
+            vec![1]
+        );
+    }
+}
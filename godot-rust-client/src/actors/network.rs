@@ -1,16 +1,94 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
 use anyhow::Result;
 use cm_shared_data::{
-    read_message, ClientNetworkMessage, Input, ServerLobbyMessage, ServerNetworkMessage,
+    clock::ClockSync, input_delay_ticks, read_message, ClientNetworkMessage, Input, LobbyPlayer,
+    ServerLobbyMessage, ServerNetworkMessage, TickBundle,
 };
+use cm_sim::{actor::SimMessage, map::Map};
 use godot::log::{godot_error, godot_print};
+use ractor::ActorRef;
 use tokio::sync::{mpsc, watch};
 
 use crate::{classes::lobby_state::LobbyState, util::network::connect};
 
+// How often to ping the server to keep our clock offset estimate fresh
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where tick bundles from the server go. They can arrive before the sim has
+/// been started so they're held until one is attached.
+#[derive(Default)]
+struct SimLink {
+    sim: Option<ActorRef<SimMessage>>,
+    pending: Vec<TickBundle>,
+    // From the server starting a game until our sim for it is detached,
+    // bundles outside of that are for a game we're not playing
+    in_game: bool,
+}
+
+impl SimLink {
+    fn start_game(&mut self) {
+        self.sim = None;
+        self.pending.clear();
+        self.in_game = true;
+    }
+
+    fn send_tick_bundle(&mut self, bundle: TickBundle) {
+        if !self.in_game {
+            return;
+        }
+        match self.sim {
+            Some(ref sim) => {
+                if let Err(e) = sim.cast(SimMessage::SendTickBundle(bundle)) {
+                    godot_error!("Failed to send tick bundle to sim: {}", e);
+                }
+            }
+            None => self.pending.push(bundle),
+        }
+    }
+
+    fn attach(&mut self, sim: ActorRef<SimMessage>) {
+        self.sim = Some(sim);
+        for bundle in std::mem::take(&mut self.pending) {
+            self.send_tick_bundle(bundle);
+        }
+    }
+
+    fn detach(&mut self) {
+        self.sim = None;
+        self.pending.clear();
+        self.in_game = false;
+    }
+}
+
+/// The server's instructions for starting the game
+#[derive(Clone)]
+pub struct GameStart {
+    pub start_at: SystemTime,
+    pub players: Vec<i32>,
+    pub map: Map,
+}
+
 enum NetworkActorMessage {
     SendInput(Input),
-    JoinLobby { name: String },
-    CreateLobby { name: String },
+    SendStateHash {
+        start_at: SystemTime,
+        tick: i32,
+        hash: u64,
+    },
+    JoinLobby {
+        name: String,
+        player_name: String,
+    },
+    CreateLobby {
+        name: String,
+        player_name: String,
+    },
+    RequestStartGame,
+    Ping,
 }
 
 struct NetworkActor {
@@ -38,21 +116,37 @@ impl NetworkActor {
     async fn handle_message(&mut self, msg: NetworkActorMessage) {
         let result = match msg {
             NetworkActorMessage::SendInput(input) => self.send_input(input).await,
-            NetworkActorMessage::CreateLobby { name } => self.send_create_lobby(name).await,
-            NetworkActorMessage::JoinLobby { name } => self.send_join_lobby(name).await,
+            NetworkActorMessage::SendStateHash {
+                start_at,
+                tick,
+                hash,
+            } => self.send_state_hash(start_at, tick, hash).await,
+            NetworkActorMessage::CreateLobby { name, player_name } => {
+                self.send_create_lobby(name, player_name).await
+            }
+            NetworkActorMessage::JoinLobby { name, player_name } => {
+                self.send_join_lobby(name, player_name).await
+            }
+            NetworkActorMessage::RequestStartGame => self.send_request_start_game().await,
+            NetworkActorMessage::Ping => self.send_ping().await,
         };
         if let Err(e) = result {
             godot_error!("{:?}", e);
         };
     }
 
-    async fn send_create_lobby(&mut self, name: String) -> Result<()> {
-        let msg = ClientNetworkMessage::create_lobby(name)?;
+    async fn send_create_lobby(&mut self, name: String, player_name: String) -> Result<()> {
+        let msg = ClientNetworkMessage::create_lobby(name, player_name)?;
+        self.send_message(msg).await
+    }
+
+    async fn send_join_lobby(&mut self, name: String, player_name: String) -> Result<()> {
+        let msg = ClientNetworkMessage::join_lobby(name, player_name)?;
         self.send_message(msg).await
     }
 
-    async fn send_join_lobby(&mut self, name: String) -> Result<()> {
-        let msg = ClientNetworkMessage::join_lobby(name)?;
+    async fn send_request_start_game(&mut self) -> Result<()> {
+        let msg = ClientNetworkMessage::request_start_game()?;
         self.send_message(msg).await
     }
 
@@ -61,6 +155,16 @@ impl NetworkActor {
         self.send_message(msg).await
     }
 
+    async fn send_state_hash(&mut self, start_at: SystemTime, tick: i32, hash: u64) -> Result<()> {
+        let msg = ClientNetworkMessage::state_hash(start_at, tick, hash)?;
+        self.send_message(msg).await
+    }
+
+    async fn send_ping(&mut self) -> Result<()> {
+        let msg = ClientNetworkMessage::ping(SystemTime::now())?;
+        self.send_message(msg).await
+    }
+
     async fn send_message(&self, bytes: Vec<u8>) -> Result<()> {
         let mut send = self.connection.open_uni().await?;
         send.write_all(&bytes).await?;
@@ -74,6 +178,17 @@ pub struct NetworkActorHandle {
     sender: mpsc::Sender<NetworkActorMessage>,
     ready: watch::Receiver<bool>,
     lobby_watch: watch::Receiver<LobbyState>,
+    game_start_tx: Arc<watch::Sender<Option<GameStart>>>,
+    game_start_watch: watch::Receiver<Option<GameStart>>,
+    lobby_error_watch: watch::Receiver<Option<String>>,
+    input_delay_watch: watch::Receiver<i32>,
+    // The name we last asked to join a lobby as
+    player_name: Arc<Mutex<String>>,
+    clock_sync: Arc<Mutex<ClockSync>>,
+    // The most ticks ahead we've scheduled an input, only ever raised like
+    // the lobby's so a later input can't land before an earlier one
+    input_delay: Arc<Mutex<i32>>,
+    sim_link: Arc<Mutex<SimLink>>,
 }
 
 impl NetworkActorHandle {
@@ -82,6 +197,18 @@ impl NetworkActorHandle {
         let (sender, receiver) = mpsc::channel(256);
         let (ready_tx, ready) = watch::channel(false);
         let (lobby_tx, lobby_watch_rx) = watch::channel(LobbyState::NotJoined);
+        let (game_start_tx, game_start_watch_rx) = watch::channel(None);
+        let game_start_tx = Arc::new(game_start_tx);
+        let game_start_tx_clone = game_start_tx.clone();
+        let (lobby_error_tx, lobby_error_watch_rx) = watch::channel(None);
+        let (input_delay_tx, input_delay_watch_rx) = watch::channel(0);
+        let sim_link = Arc::new(Mutex::new(SimLink::default()));
+        let sim_link_clone = sim_link.clone();
+        let player_name = Arc::new(Mutex::new(String::new()));
+        let player_name_clone = player_name.clone();
+        let clock_sync = Arc::new(Mutex::new(ClockSync::new()));
+        let clock_sync_clone = clock_sync.clone();
+        let ping_sender = sender.clone();
         tokio::spawn(async move {
             let connection = connect().await.expect("Cannot connect to server");
             let connection_clone = connection.clone();
@@ -101,15 +228,89 @@ impl NetworkActorHandle {
                                 ServerNetworkMessage::LobbyMessage(
                                     ServerLobbyMessage::LobbyJoined {
                                         name,
-                                        other_players: _,
+                                        player_id,
+                                        other_players,
                                     },
                                 ) => {
+                                    let mut players = other_players;
+                                    players.push(LobbyPlayer {
+                                        player_id,
+                                        name: player_name_clone.lock().unwrap().clone(),
+                                    });
+                                    players.sort_by_key(|p| p.player_id);
                                     lobby_tx.send_replace(LobbyState::Joined {
                                         name,
-                                        players: vec![],
+                                        player_id,
+                                        players,
                                     });
                                 }
-                                _ => {}
+                                ServerNetworkMessage::LobbyMessage(
+                                    ServerLobbyMessage::LobbyRosterChanged { players },
+                                ) => {
+                                    lobby_tx.send_modify(|lobby| {
+                                        if let LobbyState::Joined {
+                                            players: ref mut current,
+                                            ..
+                                        } = lobby
+                                        {
+                                            *current = players;
+                                        }
+                                    });
+                                }
+                                ServerNetworkMessage::LobbyMessage(
+                                    ServerLobbyMessage::LobbyClosed { name },
+                                ) => {
+                                    godot_print!("Lobby {} closed", name);
+                                    sim_link_clone.lock().unwrap().detach();
+                                    game_start_tx_clone.send_replace(None);
+                                    lobby_tx.send_replace(LobbyState::NotJoined);
+                                    lobby_error_tx
+                                        .send_replace(Some(format!("Lobby {} closed", name)));
+                                }
+                                ServerNetworkMessage::LobbyMessage(
+                                    ServerLobbyMessage::JoinLobbyFailed { name, reason },
+                                ) => {
+                                    godot_error!("Failed to join lobby {}: {}", name, reason);
+                                    lobby_error_tx.send_replace(Some(reason.to_string()));
+                                }
+                                ServerNetworkMessage::LobbyMessage(
+                                    ServerLobbyMessage::SynchronizedGameStart {
+                                        start_at,
+                                        players,
+                                        map,
+                                    },
+                                ) => match Map::parse(&map) {
+                                    Ok(map) => {
+                                        sim_link_clone.lock().unwrap().start_game();
+                                        game_start_tx_clone.send_replace(Some(GameStart {
+                                            start_at,
+                                            players,
+                                            map,
+                                        }));
+                                    }
+                                    // Playing on anything else would desync
+                                    // straight away
+                                    Err(e) => godot_error!("Can't play the server's map: {:?}", e),
+                                },
+                                ServerNetworkMessage::TickBundle(bundle) => {
+                                    sim_link_clone.lock().unwrap().send_tick_bundle(bundle);
+                                }
+                                ServerNetworkMessage::DesyncDetected { tick } => {
+                                    godot_error!("Desync detected at tick {}", tick);
+                                }
+                                ServerNetworkMessage::InputDelayChanged { ticks } => {
+                                    input_delay_tx.send_replace(ticks);
+                                }
+                                ServerNetworkMessage::Pong {
+                                    client_sent_at,
+                                    server_time,
+                                } => {
+                                    clock_sync_clone.lock().unwrap().record(
+                                        client_sent_at,
+                                        server_time,
+                                        SystemTime::now(),
+                                    );
+                                }
                             }
                         }
                         Err(e) => {
@@ -118,6 +319,17 @@ impl NetworkActorHandle {
                     }
                 }
             });
+
+            // Keep pinging for as long as the actor's around to send them
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(PING_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if ping_sender.send(NetworkActorMessage::Ping).await.is_err() {
+                        break;
+                    }
+                }
+            });
             actor.run().await;
         });
 
@@ -125,6 +337,14 @@ impl NetworkActorHandle {
             sender,
             ready,
             lobby_watch: lobby_watch_rx,
+            game_start_tx,
+            game_start_watch: game_start_watch_rx,
+            lobby_error_watch: lobby_error_watch_rx,
+            input_delay_watch: input_delay_watch_rx,
+            player_name,
+            clock_sync,
+            input_delay: Arc::new(Mutex::new(0)),
+            sim_link,
         }
     }
 
@@ -133,16 +353,50 @@ impl NetworkActorHandle {
         self.sender.try_send(msg).expect("Failed to send input");
     }
 
-    pub fn create_lobby(&self, name: String) {
-        let msg = NetworkActorMessage::CreateLobby { name };
+    /// Reports our state hash for `tick` of the game that started at
+    /// `start_at`
+    pub fn send_state_hash(&self, start_at: SystemTime, tick: i32, hash: u64) {
+        let msg = NetworkActorMessage::SendStateHash {
+            start_at,
+            tick,
+            hash,
+        };
+        self.sender
+            .try_send(msg)
+            .expect("Failed to send state hash");
+    }
+
+    pub fn create_lobby(&self, name: String, player_name: String) {
+        *self.player_name.lock().unwrap() = player_name.clone();
+        let msg = NetworkActorMessage::CreateLobby { name, player_name };
         self.sender.try_send(msg).expect("Failed to create lobby");
     }
 
-    pub fn join_lobby(&self, name: String) {
-        let msg = NetworkActorMessage::JoinLobby { name };
+    pub fn join_lobby(&self, name: String, player_name: String) {
+        *self.player_name.lock().unwrap() = player_name.clone();
+        let msg = NetworkActorMessage::JoinLobby { name, player_name };
         self.sender.try_send(msg).expect("Failed to join lobby");
     }
 
+    pub fn request_start_game(&self) {
+        let msg = NetworkActorMessage::RequestStartGame;
+        self.sender
+            .try_send(msg)
+            .expect("Failed to request game start");
+    }
+
+    /// Feed tick bundles received from the server into this sim
+    pub fn attach_sim(&self, sim: ActorRef<SimMessage>) {
+        self.sim_link.lock().unwrap().attach(sim);
+    }
+
+    /// Stops feeding the sim and forgets the game it was for, bundles are
+    /// ignored until the server starts another
+    pub fn detach_sim(&self) {
+        self.sim_link.lock().unwrap().detach();
+        self.game_start_tx.send_replace(None);
+    }
+
     pub fn is_connected(&self) -> bool {
         self.ready.borrow().clone()
     }
@@ -150,4 +404,45 @@ impl NetworkActorHandle {
     pub fn get_lobby_state(&self) -> LobbyState {
         self.lobby_watch.borrow().clone()
     }
+
+    /// The id the server assigned us on joining a lobby
+    pub fn get_player_id(&self) -> Option<i32> {
+        match *self.lobby_watch.borrow() {
+            LobbyState::Joined { player_id, .. } => Some(player_id),
+            LobbyState::NotJoined => None,
+        }
+    }
+
+    /// Why the last attempt to join a lobby failed, if it did
+    pub fn get_lobby_error(&self) -> Option<String> {
+        self.lobby_error_watch.borrow().clone()
+    }
+
+    /// Converts a time by the server's clock to ours
+    pub fn server_time_to_local(&self, server_time: SystemTime) -> SystemTime {
+        self.clock_sync.lock().unwrap().to_local(server_time)
+    }
+
+    /// How many ticks ahead to schedule inputs for a sim running at `speed`,
+    /// enough for our own latency and at least what the lobby asks for.
+    /// Never goes down, our latency estimate dropping between two inputs
+    /// mustn't reorder them.
+    pub fn input_delay_ticks(&self, speed: f32) -> i32 {
+        let local = {
+            let clock_sync = self.clock_sync.lock().unwrap();
+            input_delay_ticks(
+                clock_sync.rtt().unwrap_or_default(),
+                clock_sync.jitter().unwrap_or_default(),
+                speed,
+            )
+        };
+        let mut delay = self.input_delay.lock().unwrap();
+        *delay = (*delay).max(local).max(*self.input_delay_watch.borrow());
+        *delay
+    }
+
+    /// When the server has scheduled the game to start, if it has
+    pub fn get_game_start(&self) -> Option<GameStart> {
+        self.game_start_watch.borrow().clone()
+    }
 }
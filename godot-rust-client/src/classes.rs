@@ -1,2 +1,3 @@
 pub mod game_state;
 pub mod lobby_state;
+pub mod map_state;
@@ -4,27 +4,41 @@ use godot::prelude::*;
 /// A version of the game state in Godot types
 #[derive(GodotClass, GodotConvert, ToGodot)]
 pub struct GameState {
+    // The local player, whose circles are the ones they can command
+    #[var]
+    player_id: i32,
     #[var]
     circle_ids: Array<i64>,
     #[var]
     circle_positions: Array<Vector2>,
+    #[var]
+    circle_radii: Array<f32>,
+    #[var]
+    circle_player_ids: Array<i32>,
 }
 
 #[godot_api]
 impl GameState {}
 
-impl From<Game> for GameState {
-    fn from(game: Game) -> Self {
+impl GameState {
+    pub fn new(game: &Game, player_id: i32) -> Self {
         let mut id_arr = Array::<i64>::new();
         let mut pos_array = Array::<Vector2>::new();
-        for c in game.circles.iter() {
+        let mut radius_arr = Array::<f32>::new();
+        let mut owner_arr = Array::<i32>::new();
+        for c in game.circles() {
             id_arr.push(c.circle_id);
-            pos_array.push(Vector2::new(c.position.x, c.position.y));
+            pos_array.push(Vector2::new(c.position.x.to_f32(), c.position.y.to_f32()));
+            radius_arr.push(c.radius.to_f32());
+            owner_arr.push(c.player_id);
         }
 
         Self {
+            player_id,
             circle_ids: id_arr,
             circle_positions: pos_array,
+            circle_radii: radius_arr,
+            circle_player_ids: owner_arr,
         }
     }
 }
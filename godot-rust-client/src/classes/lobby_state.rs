@@ -1,9 +1,14 @@
+use cm_shared_data::LobbyPlayer;
 use godot::prelude::*;
 
 #[derive(Clone)]
 pub enum LobbyState {
     NotJoined,
-    Joined { name: String, players: Vec<String> },
+    Joined {
+        name: String,
+        player_id: i32,
+        players: Vec<LobbyPlayer>,
+    },
 }
 
 #[derive(GodotClass, GodotConvert, ToGodot)]
@@ -11,6 +16,8 @@ pub struct GLobbyState {
     #[var]
     lobby_name: GString,
     #[var]
+    player_id: i32,
+    #[var]
     players: Array<GString>,
 }
 
@@ -21,14 +28,19 @@ impl From<LobbyState> for Option<GLobbyState> {
     fn from(lobby_state: LobbyState) -> Self {
         match lobby_state {
             LobbyState::NotJoined => None,
-            LobbyState::Joined { name, players } => {
+            LobbyState::Joined {
+                name,
+                player_id,
+                players,
+            } => {
                 let mut players_arr = Array::<GString>::new();
                 for p in players.iter() {
-                    let gstring = GString::from(p);
+                    let gstring = GString::from(&p.name);
                     players_arr.push(gstring);
                 }
                 Some(GLobbyState {
                     lobby_name: GString::from(name),
+                    player_id,
                     players: players_arr,
                 })
             }
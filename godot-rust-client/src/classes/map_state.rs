@@ -0,0 +1,56 @@
+use cm_sim::{
+    fixed::FixedVec2,
+    map::{Map, Obstacle},
+};
+use godot::prelude::*;
+
+/// The map in Godot types, for drawing
+#[derive(GodotClass, GodotConvert, ToGodot)]
+pub struct MapState {
+    #[var]
+    bounds: Rect2,
+    #[var]
+    circle_centers: Array<Vector2>,
+    #[var]
+    circle_radii: Array<f32>,
+    #[var]
+    polygons: Array<PackedVector2Array>,
+}
+
+#[godot_api]
+impl MapState {}
+
+fn to_vector2(v: FixedVec2) -> Vector2 {
+    Vector2::new(v.x.to_f32(), v.y.to_f32())
+}
+
+impl From<&Map> for MapState {
+    fn from(map: &Map) -> Self {
+        let (min, max) = map.bounds();
+        let mut center_arr = Array::<Vector2>::new();
+        let mut radius_arr = Array::<f32>::new();
+        let mut polygon_arr = Array::<PackedVector2Array>::new();
+        for obstacle in map.obstacles() {
+            match obstacle {
+                Obstacle::Circle { center, radius } => {
+                    center_arr.push(to_vector2(*center));
+                    radius_arr.push(radius.to_f32());
+                }
+                Obstacle::Polygon { points } => {
+                    let mut polygon = PackedVector2Array::new();
+                    for p in points {
+                        polygon.push(to_vector2(*p));
+                    }
+                    polygon_arr.push(polygon);
+                }
+            }
+        }
+
+        Self {
+            bounds: Rect2::from_corners(to_vector2(min), to_vector2(max)),
+            circle_centers: center_arr,
+            circle_radii: radius_arr,
+            polygons: polygon_arr,
+        }
+    }
+}
@@ -2,19 +2,24 @@ mod actors;
 mod classes;
 mod util;
 
-use std::time::Duration;
+use std::time::SystemTime;
 
 use actors::network::NetworkActorHandle;
-use cm_shared_data::{Input as SimInput, InputType};
+use cm_shared_data::{Input as SimInput, InputType, TICK_DURATION};
 use cm_sim::{
-    actor::{SimActor, SimArguments, SimMessage},
-    game::Game,
+    actor::{SimActor, SimArguments, SimEvent, SimMessage},
+    game::{Game, GameEvent},
+    replay::new_replay_path,
+    simulation::{LateInputPolicy, SimSnapshot},
 };
 use godot::prelude::*;
 use ractor::{Actor, ActorRef};
-use tokio::{runtime::Runtime, sync::watch};
+use tokio::{
+    runtime::Runtime,
+    sync::{mpsc, watch},
+};
 
-use classes::{game_state::GameState, lobby_state::GLobbyState};
+use classes::{game_state::GameState, lobby_state::GLobbyState, map_state::MapState};
 
 struct CmSimExtension;
 
@@ -25,7 +30,7 @@ unsafe impl ExtensionLibrary for CmSimExtension {}
 /// In order to poll from Godot we need the actor updating the channel.
 struct SimReference {
     sim_actor: ActorRef<SimMessage>,
-    game_state_receiver: watch::Receiver<(i32, Game)>,
+    game_state_receiver: watch::Receiver<SimSnapshot>,
 }
 
 impl SimReference {
@@ -35,12 +40,23 @@ impl SimReference {
             .expect("Failed to send input");
     }
     fn get_current_tick(&self) -> i32 {
-        let (tick, _) = *self.game_state_receiver.borrow();
-        tick
+        self.game_state_receiver.borrow().tick
+    }
+    fn get_tick_lag(&self) -> i32 {
+        self.game_state_receiver.borrow().tick_lag
+    }
+    fn is_paused(&self) -> bool {
+        self.game_state_receiver.borrow().paused
+    }
+    fn get_speed(&self) -> f32 {
+        self.game_state_receiver.borrow().speed
+    }
+    fn get_game_state(&self, player_id: i32) -> Gd<GameState> {
+        let snapshot = self.game_state_receiver.borrow();
+        Gd::from_object(GameState::new(&snapshot.game, player_id))
     }
-    fn get_game_state(&self) -> Gd<GameState> {
-        let (_, game) = self.game_state_receiver.borrow().clone();
-        Gd::from_object(GameState::from(game))
+    fn get_map(&self) -> Gd<MapState> {
+        Gd::from_object(MapState::from(self.game_state_receiver.borrow().game.map()))
     }
 }
 
@@ -52,6 +68,9 @@ struct CmSimGD {
     runtime_ref: Option<Runtime>,
     network_handle: Option<NetworkActorHandle>,
     sim_ref: Option<SimReference>,
+    // When the game the sim is running was started by the server, to tell
+    // when the host restarts it
+    sim_started_at: Option<SystemTime>,
 }
 
 #[godot_api]
@@ -65,6 +84,48 @@ impl IRefCounted for CmSimGD {
             runtime_ref: None,
             network_handle: None,
             sim_ref: None,
+            sim_started_at: None,
+        }
+    }
+}
+
+impl CmSimGD {
+    /// Ours as assigned by the server, offline we're always player 0
+    fn player_id(&self) -> i32 {
+        self.network_handle
+            .as_ref()
+            .and_then(|handle| handle.get_player_id())
+            .unwrap_or(0)
+    }
+
+    /// How many ticks ahead to schedule our inputs, offline they only have
+    /// to beat the sim to the next tick
+    fn input_delay(&self) -> i32 {
+        let speed = self.sim_ref.as_ref().map_or(1.0, |sim| sim.get_speed());
+        self.network_handle
+            .as_ref()
+            .map(|handle| handle.input_delay_ticks(speed))
+            .unwrap_or(1)
+    }
+
+    /// Networked, controls go through the server like any other input so
+    /// every peer applies them on the same tick. Offline they go straight to
+    /// our sim.
+    fn send_control(&self, input_type: InputType, local: SimMessage) {
+        if let Some(ref sim) = self.sim_ref {
+            if let Some(ref handle) = self.network_handle {
+                handle.send_input(SimInput {
+                    for_tick: sim.get_current_tick() + self.input_delay(),
+                    player_id: self.player_id(),
+                    input_type,
+                });
+            } else {
+                sim.sim_actor
+                    .cast(local)
+                    .expect("Failed to send sim control");
+            }
+        } else {
+            godot_error!("Cannot control sim, sim not started")
         }
     }
 }
@@ -94,21 +155,96 @@ impl CmSimGD {
     #[func]
     fn start_sim(&mut self) {
         godot_print!("Starting sim from rust");
+        // Replacing the sim for the game we're in, the network handle gets
+        // attached to the new one below
+        if let Some(old_sim) = self.sim_ref.take() {
+            let _ = old_sim.sim_actor.cast(SimMessage::Stop);
+        }
 
         if let Some(ref rt) = self.runtime_ref {
-            let (game_state_tx, game_state_rx) =
-                watch::channel((0, Game::new(Duration::from_millis(22))));
+            let game_start = self
+                .network_handle
+                .as_ref()
+                .and_then(|handle| handle.get_game_start());
+            self.sim_started_at = game_start.as_ref().map(|start| start.start_at);
+            // Offline there's only the built in map
+            let map = game_start
+                .as_ref()
+                .map(|start| start.map.clone())
+                .unwrap_or_default();
+            let (game_state_tx, game_state_rx) = watch::channel(SimSnapshot::new(
+                0,
+                Game::with_map(TICK_DURATION, map.clone()),
+            ));
+            let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+            // Offline nobody else has to agree with us so late inputs can
+            // just happen a little later, networked they'd desync us
+            let late_input_policy = match self.network_handle {
+                Some(_) => LateInputPolicy::Reject,
+                None => LateInputPolicy::Reschedule,
+            };
+            // Unnamed, the sim being replaced may not have stopped yet and
+            // would still hold the name
             let (actor, _actor_handle) = rt
                 .block_on(Actor::spawn(
-                    Some("ClientSim".to_string()),
+                    None,
                     SimActor,
-                    // Roughly 45hz
                     SimArguments {
-                        minimum_tick_duration: Duration::from_millis(22),
+                        minimum_tick_duration: TICK_DURATION,
                         game_state_sender: game_state_tx,
+                        players: game_start
+                            .as_ref()
+                            .map(|start| start.players.clone())
+                            .unwrap_or_default(),
+                        map,
+                        replay_path: Some(new_replay_path("client")),
+                        late_input_policy,
+                        event_sender: Some(event_tx),
                     },
                 ))
                 .expect("Sim failed to start");
+
+            let hash_handle = self.network_handle.clone();
+            let hash_start_at = game_start.as_ref().map(|start| start.start_at);
+            rt.spawn(async move {
+                while let Some(event) = event_rx.recv().await {
+                    match event {
+                        SimEvent::LateInput(late) => godot_print!(
+                            "Input for tick {} arrived on tick {} ({:?})",
+                            late.input.for_tick,
+                            late.current_tick,
+                            late.policy
+                        ),
+                        SimEvent::ReplayFailed(e) => godot_error!("{}", e),
+                        // Report our state hash so the server can detect desyncs
+                        SimEvent::StateHash { tick, hash } => {
+                            if let (Some(ref handle), Some(start_at)) =
+                                (&hash_handle, hash_start_at)
+                            {
+                                handle.send_state_hash(start_at, tick, hash);
+                            }
+                        }
+                        SimEvent::Game {
+                            tick,
+                            event: GameEvent::Arrived { circle_id },
+                        } => godot_print!("Circle {} arrived on tick {}", circle_id, tick),
+                    }
+                }
+            });
+
+            if let Some(ref handle) = self.network_handle {
+                // Everyone's inputs arrive as tick bundles from the server
+                handle.attach_sim(actor.clone());
+                match game_start {
+                    Some(start) => actor
+                        .cast(SimMessage::StartAt(
+                            handle.server_time_to_local(start.start_at),
+                        ))
+                        .expect("Failed to schedule sim start"),
+                    None => godot_error!("Starting sim before the server started the game"),
+                }
+            }
+
             self.sim_ref = Some(SimReference {
                 sim_actor: actor,
                 game_state_receiver: game_state_rx,
@@ -116,34 +252,70 @@ impl CmSimGD {
         }
     }
 
+    /// Whether the server has started a new game since our sim was started,
+    /// the sim needs starting again to play it
+    #[func]
+    fn is_game_restarting(&self) -> bool {
+        match (&self.sim_ref, &self.network_handle) {
+            (Some(_), Some(handle)) => handle
+                .get_game_start()
+                .is_some_and(|start| Some(start.start_at) != self.sim_started_at),
+            _ => false,
+        }
+    }
+
     #[func]
-    fn stop_sim(&self) {
+    fn stop_sim(&mut self) {
         godot_print!("Stopping sim");
-        todo!();
+        self.sim_started_at = None;
+        if let Some(ref handle) = self.network_handle {
+            handle.detach_sim();
+        }
+        if let Some(sim) = self.sim_ref.take() {
+            if let Err(e) = sim.sim_actor.cast(SimMessage::Stop) {
+                godot_error!("Failed to stop sim: {}", e);
+            }
+        }
     }
 
     #[func]
     fn get_latest_state(&mut self) -> Option<Gd<GameState>> {
         if let Some(ref sim) = self.sim_ref {
-            Some(sim.get_game_state())
+            Some(sim.get_game_state(self.player_id()))
         } else {
             None
         }
     }
 
+    #[func]
+    fn get_map(&self) -> Option<Gd<MapState>> {
+        self.sim_ref.as_ref().map(|sim| sim.get_map())
+    }
+
+    /// How many ticks the sim is behind the wall clock
+    #[func]
+    fn get_tick_lag(&self) -> i32 {
+        match self.sim_ref {
+            Some(ref sim) => sim.get_tick_lag(),
+            None => 0,
+        }
+    }
+
     #[func]
     fn add_circle(&mut self, pos: Vector2) {
         if let Some(ref sim) = self.sim_ref {
             let tick = sim.get_current_tick();
-            // TODO: Figure out latency for tick
             let input = SimInput {
-                for_tick: tick + 1,
-                player_id: 0,
+                for_tick: tick + self.input_delay(),
+                player_id: self.player_id(),
                 input_type: InputType::CreateCircle { x: pos.x, y: pos.y },
             };
-            sim.send_input(input);
+            // When networked the server bundles it into a tick for everyone,
+            // including us
             if let Some(ref handle) = self.network_handle {
                 handle.send_input(input);
+            } else {
+                sim.send_input(input);
             }
         } else {
             godot_error!("Cannot add circle, sim not started")
@@ -155,18 +327,18 @@ impl CmSimGD {
         if let Some(ref sim) = self.sim_ref {
             let tick = sim.get_current_tick();
             let input = SimInput {
-                // FIXME: Actually deal with latency
-                for_tick: tick + 1,
-                player_id: 0,
+                for_tick: tick + self.input_delay(),
+                player_id: self.player_id(),
                 input_type: InputType::SetDestination {
                     circle_id,
                     x: pos.x,
                     y: pos.y,
                 },
             };
-            sim.send_input(input);
             if let Some(ref handle) = self.network_handle {
                 handle.send_input(input);
+            } else {
+                sim.send_input(input);
             }
         } else {
             godot_error!("Cannot set destination, sim not started")
@@ -174,19 +346,65 @@ impl CmSimGD {
     }
 
     #[func]
-    fn join_lobby(&self, name: String) {
+    fn pause(&self) {
+        self.send_control(InputType::Pause, SimMessage::Pause);
+    }
+
+    #[func]
+    fn resume(&self) {
+        self.send_control(InputType::Resume, SimMessage::Resume);
+    }
+
+    #[func]
+    fn set_speed(&self, speed: f32) {
+        self.send_control(InputType::SetSpeed { speed }, SimMessage::SetSpeed(speed));
+    }
+
+    #[func]
+    fn is_paused(&self) -> bool {
+        match self.sim_ref {
+            Some(ref sim) => sim.is_paused(),
+            None => false,
+        }
+    }
+
+    #[func]
+    fn join_lobby(&self, name: String, player_name: String) {
+        if let Some(ref handle) = self.network_handle {
+            handle.join_lobby(name, player_name);
+        }
+    }
+
+    #[func]
+    fn create_lobby(&self, name: String, player_name: String) {
         if let Some(ref handle) = self.network_handle {
-            handle.join_lobby(name);
+            handle.create_lobby(name, player_name);
         }
     }
 
     #[func]
-    fn create_lobby(&self, name: String) {
+    fn request_start_game(&self) {
         if let Some(ref handle) = self.network_handle {
-            handle.create_lobby(name);
+            handle.request_start_game();
         }
     }
 
+    #[func]
+    fn is_game_starting(&self) -> bool {
+        self.network_handle
+            .as_ref()
+            .is_some_and(|h| h.get_game_start().is_some())
+    }
+
+    #[func]
+    fn get_lobby_error(&self) -> GString {
+        self.network_handle
+            .as_ref()
+            .and_then(|handle| handle.get_lobby_error())
+            .map(GString::from)
+            .unwrap_or_default()
+    }
+
     #[func]
     fn get_lobby_state(&self) -> Option<Gd<GLobbyState>> {
         if let Some(nh) = &self.network_handle {
@@ -1,5 +1,11 @@
 extends Node2D
 
+var radius: float = 5.0:
+	set(value):
+		if value != radius:
+			radius = value
+			queue_redraw()
+
 # Called when the node enters the scene tree for the first time.
 func _ready():
 	pass # Replace with function body.
@@ -10,4 +16,4 @@ func _process(delta):
 	pass
 
 func _draw():
-	draw_circle(Vector2(0,0), 5, Color.RED)
+	draw_circle(Vector2(0,0), radius, Color.RED)
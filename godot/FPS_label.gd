@@ -2,4 +2,4 @@ extends Label
 
 # Called every frame. 'delta' is the elapsed time since the previous frame.
 func _process(delta):
-	set_text("FPS %d" % Engine.get_frames_per_second())
+	set_text("FPS %d, %d ticks behind" % [Engine.get_frames_per_second(), Brain.brain.get_tick_lag()])
@@ -1,41 +1,86 @@
 extends Node2D
 
 @onready 
-var sim = CmSimGD.new()
+var sim = Brain.brain
 
 var circle_scn = preload("res://circle.tscn")
 
 var circles_by_id = {}
 
+# The circles the local player can command
+var own_circle_ids = []
+
+var map = null
+
 func _ready():
 	print("godot root _ready")
 	sim.start_sim()
 	print("sim started")
+	map = sim.get_map()
+	queue_redraw()
+
+func _draw():
+	if map == null:
+		return
+	draw_rect(map.bounds, Color.DIM_GRAY, false, 2.0)
+	for i in range(map.circle_centers.size()):
+		draw_circle(map.circle_centers[i], map.circle_radii[i], Color.DIM_GRAY)
+	for polygon in map.polygons:
+		draw_colored_polygon(polygon, Color.DIM_GRAY)
 
 func _process(dt):
-	pass
-	#var state = sim.get_latest_state()
-	## The Godot process is ticking faster than the sim,
-	## we'll get null here if there hasn't been any updates
-	#if state != null:
-		#for i in range(state.circle_ids.size()):
-			#var circle_id = state.circle_ids[i]
-			#var circle_node: Node2D = circles_by_id.get(circle_id)
-			#if circle_node == null:
-				#print("Adding circle ", circle_id)
-				#circle_node = circle_scn.instantiate()
-				#circles_by_id[circle_id] = circle_node
-				#add_child(circle_node)
-			#circle_node.position = state.circle_positions[i]
+	# The lobby closed mid game, there's nobody left to play with
+	if sim.get_lobby_state() == null:
+		sim.stop_sim()
+		get_tree().change_scene_to_file("res://menu.tscn")
+		return
+	# The host started the game over, play the new one
+	if sim.is_game_restarting():
+		for circle_node in circles_by_id.values():
+			circle_node.queue_free()
+		circles_by_id.clear()
+		sim.start_sim()
+	var state = sim.get_latest_state()
+	# The Godot process is ticking faster than the sim,
+	# we'll get null here if there hasn't been any updates
+	if state != null:
+		own_circle_ids.clear()
+		for i in range(state.circle_ids.size()):
+			var circle_id = state.circle_ids[i]
+			var circle_node: Node2D = circles_by_id.get(circle_id)
+			if circle_node == null:
+				print("Adding circle ", circle_id)
+				circle_node = circle_scn.instantiate()
+				circles_by_id[circle_id] = circle_node
+				add_child(circle_node)
+			circle_node.position = state.circle_positions[i]
+			circle_node.radius = state.circle_radii[i]
+			if state.circle_player_ids[i] == state.player_id:
+				own_circle_ids.append(circle_id)
 
 func _input(event):
-	pass
+	if event is InputEventKey and event.pressed and not event.echo:
+		match event.keycode:
+			KEY_ESCAPE:
+				sim.stop_sim()
+				get_tree().change_scene_to_file("res://lobby.tscn")
+			KEY_SPACE:
+				if sim.is_paused():
+					sim.resume()
+				else:
+					sim.pause()
+			KEY_1:
+				sim.set_speed(1.0)
+			KEY_2:
+				sim.set_speed(2.0)
+			KEY_4:
+				sim.set_speed(4.0)
 	# Mouse in viewport coordinates.
-	#if event is InputEventMouseButton:
-		#var view_to_world = get_canvas_transform().affine_inverse()
-		#var world_pos = view_to_world * event.position
-		#if event.button_index == MOUSE_BUTTON_RIGHT and event.pressed:
-			#sim.add_circle(world_pos)
-		#if event.button_index == MOUSE_BUTTON_LEFT and event.pressed:
-			#for id in circles_by_id.keys():
-				#sim.set_destination(id, world_pos)
+	if event is InputEventMouseButton:
+		var view_to_world = get_canvas_transform().affine_inverse()
+		var world_pos = view_to_world * event.position
+		if event.button_index == MOUSE_BUTTON_RIGHT and event.pressed:
+			sim.add_circle(world_pos)
+		if event.button_index == MOUSE_BUTTON_LEFT and event.pressed:
+			for id in own_circle_ids:
+				sim.set_destination(id, world_pos)
@@ -3,6 +3,12 @@ extends Control
 @onready
 var lobby_name_label = $MarginContainer/VBoxContainer/LobbyNameLabel
 
+@onready
+var players_label = $MarginContainer/VBoxContainer/PlayersLabel
+
+@onready
+var start_game_button = $MarginContainer/VBoxContainer/StartGameButton
+
 # Called when the node enters the scene tree for the first time.
 func _ready():
 	var lobby_state = Brain.brain.get_lobby_state()
@@ -10,8 +16,17 @@ func _ready():
 
 # Called every frame. 'delta' is the elapsed time since the previous frame.
 func _process(delta):
-	pass
+	var lobby_state = Brain.brain.get_lobby_state()
+	# The lobby closed on us
+	if lobby_state == null:
+		get_tree().change_scene_to_file("res://menu.tscn")
+		return
+	players_label.text = "\n".join(lobby_state.players)
+	# Only the host can start the game
+	start_game_button.visible = lobby_state.player_id == 0
+	if Brain.brain.is_game_starting():
+		get_tree().change_scene_to_file("res://root.tscn")
 
 
 func _on_start_game_button_pressed():
-	pass # Replace with function body.
+	Brain.brain.request_start_game()
@@ -6,6 +6,9 @@ var lobby_name_text_edit := $MarginContainer/VBoxContainer/LobbyNameTextEdit
 @onready
 var player_name_text_edit := $MarginContainer/VBoxContainer/PlayerNameTextEdit
 
+@onready
+var error_label := $MarginContainer/VBoxContainer/ErrorLabel
+
 # Called when the node enters the scene tree for the first time.
 func _ready():
 	pass
@@ -16,12 +19,13 @@ func _process(delta):
 	var lobby_state = Brain.brain.get_lobby_state()
 	if lobby_state != null:
 		get_tree().change_scene_to_file("res://lobby.tscn")
+	error_label.text = Brain.brain.get_lobby_error()
 
 func _on_join_lobby_button_pressed():
-	if lobby_name_text_edit.text != "":
-		Brain.brain.join_lobby(lobby_name_text_edit.text)
+	if lobby_name_text_edit.text != "" and player_name_text_edit.text != "":
+		Brain.brain.join_lobby(lobby_name_text_edit.text, player_name_text_edit.text)
 
 
 func _on_create_lobby_button_pressed():
-	if lobby_name_text_edit.text != "":
-		Brain.brain.create_lobby(lobby_name_text_edit.text)
+	if lobby_name_text_edit.text != "" and player_name_text_edit.text != "":
+		Brain.brain.create_lobby(lobby_name_text_edit.text, player_name_text_edit.text)